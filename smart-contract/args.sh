@@ -1 +1,3 @@
 #!/usr/bin/env node
+// Prints the `new` init args, with a random salt so chat ids are unique to this deployment.
+console.log(JSON.stringify({ salt: require("crypto").randomBytes(16).toString("hex") }));
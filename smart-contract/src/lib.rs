@@ -1,20 +1,201 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use near_sdk::{
+    assert_one_yocto,
     borsh::{self, BorshDeserialize, BorshSerialize},
-    env, near_bindgen, require,
+    collections::TreeMap,
+    env, ext_contract, is_promise_success,
+    json_types::{Base58CryptoHash, U128},
+    near_bindgen, require,
     serde::{Deserialize, Serialize},
-    store::{LookupMap, UnorderedSet, Vector},
-    AccountId, BorshStorageKey, CryptoHash, PanicOnDefault,
+    store::{LookupMap, LookupSet, UnorderedMap, UnorderedSet, Vector},
+    AccountId, BorshStorageKey, CryptoHash, CurveType, Gas, PanicOnDefault, Promise, PromiseError,
+    PromiseOrValue, PublicKey,
 };
 
+/// Gas attached to the membership badge `nft_mint` call.
+const GAS_FOR_NFT_MINT: Gas = Gas(20_000_000_000_000);
+/// Gas reserved for the `on_membership_minted` callback.
+const GAS_FOR_ON_MEMBERSHIP_MINTED: Gas = Gas(10_000_000_000_000);
+/// Gas reserved for the `on_tip_transferred` callback.
+const GAS_FOR_ON_TIP_TRANSFERRED: Gas = Gas(10_000_000_000_000);
+/// Gas attached to the premium token `ft_balance_of` call.
+const GAS_FOR_FT_BALANCE_OF: Gas = Gas(10_000_000_000_000);
+/// Gas reserved for the `on_premium_balance_checked` callback.
+const GAS_FOR_ON_PREMIUM_BALANCE_CHECKED: Gas = Gas(20_000_000_000_000);
+/// Gas attached to the credit token `ft_transfer` call.
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+/// Gas reserved for the `on_credits_withdrawn` callback.
+const GAS_FOR_ON_CREDITS_WITHDRAWN: Gas = Gas(10_000_000_000_000);
+
+/// Standard name of the NEP-297 events emitted by this contract.
+const EVENT_STANDARD: &str = "chat";
+/// Version of the NEP-297 events emitted by this contract.
+const EVENT_VERSION: &str = "1.0.0";
+
 #[derive(BorshSerialize, BorshStorageKey)]
 enum StorageKey {
     Users,
     Messages,
-    Message,
+    Message { chat_id: CryptoHash },
     Friends,
     FriendOfUser { user_id: AccountId },
+    ChatParticipants,
+    Profiles,
+    ReadReceipts,
+    ReadReceiptsOfUser { user_id: AccountId },
+    ChatPartners,
+    ChatPartnersOfUser { user_id: AccountId },
+    RecoveryDelegates,
+    PendingRecoveries,
+    FrozenAccounts,
+    MessagesSent,
+    JoinedAt,
+    IncomingRequests,
+    IncomingRequestsOfUser { user_id: AccountId },
+    OutgoingRequests,
+    OutgoingRequestsOfUser { user_id: AccountId },
+    Moderators,
+    Groups,
+    GroupMembers { group_id: u64 },
+    GroupMessages { group_id: u64 },
+    MessageRepeats,
+    Reactions,
+    ReactionsOfMessage { message_id: u64 },
+    Reactors { message_id: u64, emoji: String },
+    Usernames,
+    UsernameOfAccount,
+    GroupMutes { group_id: u64 },
+    MessageTips,
+    AllowedReactions,
+    FriendCounts,
+    EncryptedChats,
+    BlockedUsers,
+    BlockedUsersOfUser { user_id: AccountId },
+    Drafts,
+    DraftsOfUser { user_id: AccountId },
+    PollVotes,
+    PollVotesOfMessage { message_id: u64 },
+    PollTallies,
+    BannedSubstrings,
+    DeletedAt,
+    Reports,
+    FriendEdges,
+    LastActive,
+    FriendLabels,
+    FriendLabelsOfUser { user_id: AccountId },
+    DeletedMessages,
+    ChatSubjects,
+    ChatIds,
+    IncomingRequestCounts,
+    HiddenMessages,
+    HiddenMessagesOfUser { user_id: AccountId },
+    SortedUsers,
+    ChatPresence,
+    Verified,
+    Relayers,
+    RelayKeys,
+    RelayNonces,
+    AutoAccept,
+    AutoAcceptOfUser { user_id: AccountId },
+    Balances,
+    NotificationPrefs,
+    ClientNonces,
+    GroupMemberships,
+    GroupMembershipsOfUser { user_id: AccountId },
+    MigratedFriends,
+    MergedLegacyChats,
 }
 
+/// Default number of identical consecutive messages a user can send to a chat.
+const DEFAULT_MAX_REPEATED_MESSAGES: u32 = 3;
+/// Maximum number of chat ids accepted by `prune_empty_chats` in a single call.
+const MAX_PRUNE_BATCH: usize = 50;
+/// Maximum number of account pairs accepted by `recanonicalize_chats` in a single call.
+const MAX_RECANONICALIZE_BATCH: usize = 20;
+/// Maximum number of account pairs accepted by `audit_chat_id_collision` in a single call.
+const MAX_AUDIT_BATCH: usize = 100;
+/// Maximum number of users removed by a single `admin_reset` call.
+const MAX_RESET_BATCH: u32 = 20;
+/// Maximum number of accounts accepted by `admin_register_accounts` in a single call.
+const MAX_REGISTER_BATCH: usize = 50;
+/// Maximum number of accounts returned in each list of a `SocialState`.
+const MAX_SOCIAL_STATE_LIST: usize = 100;
+/// Maximum number of banned substrings, bounding the gas spent checking messages.
+const MAX_BANNED_SUBSTRINGS: u32 = 50;
+/// Maximum number of messages included by `export_chat`.
+const MAX_EXPORT_MESSAGES: usize = 500;
+/// Maximum number of users, or of messages for the message pages, returned by a
+/// paginated getter in a single call.
+const MAX_USERS_PAGE: u32 = 50;
+/// Maximum number of accounts accepted by `bulk_get_profiles` in a single call.
+const MAX_PROFILES_BATCH: usize = 100;
+/// Maximum number of entries accepted by `batch_mark_read` in a single call.
+const MAX_MARK_READ_BATCH: usize = 50;
+/// Default number of friendships and chats cleaned by a single `force_remove_user` call.
+const DEFAULT_REMOVAL_LIMIT: u32 = 50;
+/// Default maximum number of members in a group, including the admin.
+const DEFAULT_MAX_GROUP_MEMBERS: u32 = 256;
+/// Maximum number of entries a filtering view reads before returning what it found.
+const MAX_SCAN: usize = 500;
+/// Maximum number of chats scanned by views aggregating over a user's chats.
+const MAX_CHATS_SCAN: usize = 100;
+/// Time a recovery delegate has to wait between `initiate_recovery` and `complete_recovery`.
+const RECOVERY_DELAY_MS: u64 = 24 * 60 * 60 * 1000;
+/// Maximum length, in bytes, of a chat subject.
+const MAX_CHAT_SUBJECT_LEN: usize = 100;
+/// Default time the author of a message has to edit it.
+const DEFAULT_EDIT_WINDOW_MS: u64 = 15 * 60 * 1000;
+/// Time the author of a message has to delete it for everyone.
+const DELETE_FOR_EVERYONE_WINDOW_MS: u64 = 60 * 60 * 1000;
+/// Time the author of a deleted message has to restore it.
+const RESTORE_WINDOW_MS: u64 = 5 * 60 * 1000;
+/// Maximum length, in bytes, of the content quoted by `send_quote`.
+const MAX_QUOTE_LEN: usize = 500;
+/// Maximum length, in bytes, of a group name.
+const MAX_GROUP_NAME_LEN: usize = 64;
+/// Minimum length of a username.
+const MIN_USERNAME_LEN: usize = 3;
+/// Maximum length of a username.
+const MAX_USERNAME_LEN: usize = 32;
+/// Maximum length, in bytes, of a profile display name.
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+/// Maximum length, in bytes, of a profile bio.
+const MAX_BIO_LEN: usize = 280;
+/// Approximate storage, in bytes, used by a message besides its content.
+const MESSAGE_STORAGE_OVERHEAD: u64 = 200;
+/// Minimum number of options of a poll.
+const MIN_POLL_OPTIONS: usize = 2;
+/// Maximum number of options of a poll.
+const MAX_POLL_OPTIONS: usize = 10;
+/// Maximum length, in bytes, of a poll option.
+const MAX_POLL_OPTION_LEN: usize = 100;
+/// Maximum length, in bytes, of the reason of a report.
+const MAX_REPORT_REASON_LEN: usize = 280;
+/// Maximum length, in bytes, of the welcome message posted to new users.
+const MAX_WELCOME_MESSAGE_LEN: usize = 1000;
+/// Maximum length, in bytes, of a draft.
+const MAX_DRAFT_LEN: usize = 2000;
+/// Maximum number of ancestors returned by `get_reply_chain`.
+const MAX_REPLY_CHAIN_DEPTH: usize = 20;
+/// Maximum number of messages on each side returned by `get_message_context`.
+const MAX_CONTEXT_RADIUS: u32 = 25;
+/// Maximum length, in bytes, of a reaction emoji.
+const MAX_REACTION_LEN: usize = 32;
+/// Maximum number of labels on a friend.
+const MAX_FRIEND_LABELS: usize = 10;
+/// Maximum length, in bytes, of a friend label.
+const MAX_FRIEND_LABEL_LEN: usize = 32;
+/// Length, in bytes, of a decoded ed25519 signature.
+const ED25519_SIGNATURE_LEN: usize = 64;
+/// Maximum length, in bytes, of a `send_message` client nonce.
+const MAX_CLIENT_NONCE_LEN: usize = 64;
+/// Number of most recent client nonces remembered per chat.
+const MAX_CLIENT_NONCES: usize = 20;
+/// Message ids below this are left for the baseline messages merged by
+/// `recanonicalize_chats` after `migrate`, so that ids keep increasing within a chat.
+const LEGACY_MESSAGE_IDS: u64 = 1 << 32;
+
 #[near_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
 pub struct Contract {
@@ -24,179 +205,5051 @@ pub struct Contract {
     ///
     /// Note: chat_id is the hash of `user_id` and `receiver_id`
     pub messages: LookupMap<CryptoHash, Vector<Message>>,
-    /// The `friends` mapping is used to store information about user friendships.
-    /// It is a two-tier mapping, where the outer mapping associates a user's `AccountId`
-    /// with their friend's `AccountId`. The inner mapping stores a boolean value that
-    /// indicates whether the users are friends (true) or not (false).
-    pub friends: LookupMap<AccountId, LookupMap<AccountId, bool>>,
+    /// A mapping from user_id to the set of its friends, kept symmetric
+    ///
+    /// Note: a set rather than a map to an always-true flag, saving the one-byte
+    /// borsh-encoded flag on each side of a friendship.
+    pub friends: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    /// A mapping from chat_id to its two participants, in ascending order
+    pub chat_participants: LookupMap<CryptoHash, (AccountId, AccountId)>,
+    /// A mapping from user_id to the profile the user has set
+    pub profiles: LookupMap<AccountId, Profile>,
+    /// A mapping from user_id to the index of the last message the user has read in each chat
+    pub read_receipts: LookupMap<AccountId, LookupMap<CryptoHash, u32>>,
+    /// A mapping from user_id to the set of users they have a chat with
+    pub chat_partners: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    /// A mapping from user_id to the account allowed to freeze it if it is compromised
+    pub recovery_delegates: LookupMap<AccountId, AccountId>,
+    /// A mapping from user_id to the time its delegate initiated a recovery
+    pub pending_recoveries: LookupMap<AccountId, u64>,
+    /// A set of account ids whose writes are paused by a completed recovery
+    pub frozen_accounts: LookupSet<AccountId>,
+    /// A mapping from user_id to the number of messages the user has sent
+    pub messages_sent: LookupMap<AccountId, u64>,
+    /// A mapping from user_id to the time the user registered
+    pub joined_at_ms: LookupMap<AccountId, u64>,
+    /// A mapping from user_id to the accounts that sent the user a pending friend request
+    pub incoming_requests: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    /// A mapping from user_id to the accounts the user sent a pending friend request to
+    pub outgoing_requests: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    /// A mapping from user_id to the accounts whose friend requests the user accepts at once
+    pub auto_accept: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    /// A mapping from user_id to the notifications the user wants clients to push
+    pub notification_prefs: LookupMap<AccountId, NotificationPrefs>,
+    /// A set of account ids allowed to moderate users, managed by the owner
+    pub moderators: UnorderedSet<AccountId>,
+    /// A set of account ids, e.g. official or support ones, that can message anyone
+    /// without being friends, managed by the owner
+    pub verified: UnorderedSet<AccountId>,
+    /// A set of account ids allowed to submit messages on behalf of users, managed by the owner
+    pub relayers: UnorderedSet<AccountId>,
+    /// A mapping from user_id to the ed25519 key its relayed messages must be signed with
+    pub relay_keys: LookupMap<AccountId, PublicKey>,
+    /// A mapping from user_id to the nonce of its last relayed message
+    pub relay_nonces: LookupMap<AccountId, u64>,
+    /// The account allowed to change contract-level settings
+    pub owner_id: AccountId,
+    /// The NFT contract minting membership badges on registration
+    pub membership_minter_id: Option<AccountId>,
+    /// Whether `create_account` mints a membership badge before registering the user
+    pub membership_badge_enabled: bool,
+    /// The id assigned to the next message sent, in any chat
+    pub next_message_id: u64,
+    /// Whether users have to be friends, or accept messages from anyone, to receive messages
+    pub require_friendship_to_message: bool,
+    /// A mapping from group_id to group chat
+    pub groups: LookupMap<u64, Group>,
+    /// The id assigned to the next group created
+    pub next_group_id: u64,
+    /// The fungible token users must hold to use premium features, if any
+    pub premium_token_id: Option<AccountId>,
+    /// The minimum balance of `premium_token_id` required for premium features
+    pub premium_min_balance: u128,
+    /// The fungible token users deposit, with `ft_transfer_call`, to get in-app credits
+    pub credit_token_id: Option<AccountId>,
+    /// A mapping from user_id to its in-app credits, in units of `credit_token_id`
+    pub balances: LookupMap<AccountId, u128>,
+    /// The credits deposited and not yet withdrawn, including withdrawals in flight
+    pub total_credits: u128,
+    /// A mapping from chat_id to the latest run of identical messages in it
+    pub message_repeats: LookupMap<CryptoHash, MessageRepeat>,
+    /// How many identical consecutive messages a user can send to a chat, 0 for no limit
+    pub max_repeated_messages: u32,
+    /// The maximum number of registered users
+    pub max_users: u32,
+    /// The maximum number of members in a group, including the admin
+    pub max_group_members: u32,
+    /// A mapping from message id to the accounts that reacted to it with each emoji
+    pub reactions: LookupMap<u64, UnorderedMap<String, UnorderedSet<AccountId>>>,
+    /// Mixed into chat ids so that they differ across deployments
+    pub salt: String,
+    /// A mapping from a unique username to the account that claimed it
+    pub usernames: LookupMap<String, AccountId>,
+    /// A mapping from account to its claimed username
+    pub username_of_account: LookupMap<AccountId, String>,
+    /// A mapping from message id to the total tips, in yoctoNEAR, its author received
+    pub message_tips: LookupMap<u64, u128>,
+    /// The emojis users can react with, any emoji if empty
+    pub allowed_reactions: UnorderedSet<String>,
+    /// A mapping from user to its number of friends, kept in sync with `friends`
+    pub friend_counts: LookupMap<AccountId, u32>,
+    /// Chats in which only client-side encrypted messages are accepted
+    pub encrypted_chats: LookupSet<CryptoHash>,
+    /// A mapping from user to the accounts it blocked
+    pub blocked_users: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    /// A mapping from user to its unsent draft in each chat
+    pub drafts: LookupMap<AccountId, LookupMap<CryptoHash, String>>,
+    /// A mapping from poll message id to the option each participant voted for
+    pub poll_votes: LookupMap<u64, LookupMap<AccountId, u32>>,
+    /// A mapping from poll message id to the number of votes for each option
+    pub poll_tallies: LookupMap<u64, Vec<u32>>,
+    /// Cost of a byte of storage, in yoctoNEAR, used instead of the protocol's if set
+    pub storage_cost_override: Option<u128>,
+    /// Lowercase substrings plaintext messages can not contain
+    pub banned_substrings: Vector<String>,
+    /// The number of messages sent since deployment, across chats and groups
+    pub total_messages: u64,
+    /// The number of current friendships, each counted once
+    pub total_friendships: u64,
+    /// A mapping from removed account to when it was removed, in milliseconds
+    pub deleted_at_ms: LookupMap<AccountId, u64>,
+    /// How long a removed account has to wait before registering again, in milliseconds
+    pub reregistration_cooldown_ms: u64,
+    /// Reports filed against accounts, indexed by report id
+    pub reports: Vector<Report>,
+    /// Every friendship as a single edge, see `edge_id`. This is the source of truth,
+    /// `friends` only indexes the edges by account so they can be listed
+    ///
+    /// Note: the edge is stored on top of that index, so a friendship takes more
+    /// storage than with `friends` alone, about 150 bytes more next to the index's
+    /// 290. What it buys is that `are_friends` reads a single key.
+    pub friend_edges: UnorderedSet<CryptoHash>,
+    /// A mapping from user to when they last sent a message or heartbeat, in milliseconds
+    pub last_active_ms: LookupMap<AccountId, u64>,
+    /// A mapping from user to the labels, e.g. "close" or "work", they gave their friends
+    pub friend_labels: LookupMap<AccountId, LookupMap<AccountId, Vec<String>>>,
+    /// A mapping from message id to what was erased when it was deleted, kept for restores
+    pub deleted_messages: LookupMap<u64, DeletedContent>,
+    /// A mapping from chat_id to the subject its participants gave it
+    pub chat_subjects: LookupMap<CryptoHash, String>,
+    /// The ids of every chat in `chat_participants`, so that chats can be iterated
+    pub chat_ids: UnorderedSet<CryptoHash>,
+    /// How long new messages stay visible once sent, in milliseconds, if set
+    pub default_message_ttl_ms: Option<u64>,
+    /// A mapping from user to the number of its pending incoming friend requests
+    pub incoming_request_counts: LookupMap<AccountId, u32>,
+    /// A mapping from user to the ids of the messages they deleted for themselves only
+    pub hidden_messages: LookupMap<AccountId, UnorderedSet<u64>>,
+    /// Which accounts can register with `create_account`
+    pub account_policy: AccountPolicy,
+    /// The same accounts as `users`, sorted so that they can be paginated with a cursor
+    pub sorted_users: TreeMap<AccountId, ()>,
+    /// Whether views list who reacted to messages, or only how many did
+    pub reactions_public: bool,
+    /// Posted to the self-chat of every new user, unless empty
+    pub welcome_message: String,
+    /// How long after sending a message its author can edit it, in milliseconds
+    pub edit_window_ms: u64,
+    /// The account `propose_owner` offered the ownership to, until it accepts
+    pub pending_owner_id: Option<AccountId>,
+    /// A mapping from chat_id to when each participant, in the order of
+    /// `chat_participants`, last pinged the chat
+    pub chat_presence: LookupMap<CryptoHash, (Option<u64>, Option<u64>)>,
+    /// A mapping from chat_id to the last `MAX_CLIENT_NONCES` client nonces sent to it,
+    /// oldest first
+    pub client_nonces: LookupMap<CryptoHash, Vec<ClientNonce>>,
+    /// A mapping from user_id to the ids of the groups the user is a member of
+    pub group_memberships: LookupMap<AccountId, UnorderedSet<u64>>,
+    /// The legacy chat ids, with the account that sent their messages, already merged
+    /// by `recanonicalize_chats`
+    pub merged_legacy_chats: LookupSet<(CryptoHash, AccountId)>,
+    /// The id the next baseline message merged by `recanonicalize_chats` gets
+    pub next_legacy_message_id: u64,
+}
+
+/// The state of the baseline contract, before chat ids were canonical, read by `migrate`.
+///
+/// The baseline stored the messages of every chat under the same `StorageKey::Message`
+/// prefix, so chats overwrote each other's messages at the same index.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct LegacyContract {
+    users: UnorderedSet<AccountId>,
+    messages: LookupMap<CryptoHash, Vector<LegacyMessage>>,
+    friends: LookupMap<AccountId, LookupMap<AccountId, bool>>,
+}
+
+/// A message as the baseline contract stored it.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyMessage {
+    pub author: AccountId,
+    pub content: String,
+    pub created_at_ms: u64,
+}
+
+/// The content of a deleted message, kept for `RESTORE_WINDOW_MS` after the deletion.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct DeletedContent {
+    pub content: String,
+    pub quote: Option<Quote>,
+    pub deleted_at_ms: u64,
+}
+
+/// A run of identical consecutive messages from the same author in a chat.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MessageRepeat {
+    pub author: AccountId,
+    pub content_hash: CryptoHash,
+    pub count: u32,
+}
+
+/// Where a message sent with `send_message` landed, so clients can render it right away.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SentMessage {
+    pub chat_id: Base58CryptoHash,
+    pub index: u32,
+    pub id: u64,
+    pub created_at_ms: u64,
+}
+
+/// A client nonce `send_message` was called with, and where that message landed.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ClientNonce {
+    pub author: AccountId,
+    pub nonce: String,
+    pub sent: SentMessage,
+}
+
+/// Outcome of `add_friend`, so clients can tell a new friendship from a no-op.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FriendAddResult {
+    Added,
+    AlreadyFriends,
+}
+
+/// Where a report is in the moderation queue.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ReportStatus {
+    Open,
+    Resolved,
+    Dismissed,
+}
+
+/// A user's report of another account, triaged by moderators.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Report {
+    pub id: u32,
+    pub reporter_id: AccountId,
+    pub reported_id: AccountId,
+    pub reason: String,
+    pub created_at_ms: u64,
+    pub status: ReportStatus,
+}
+
+/// Contract-wide health metrics for operators.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractStats {
+    pub users: u32,
+    pub total_messages: u64,
+    pub total_friendships: u64,
+    pub storage_usage: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UserStats {
+    pub messages_sent: u64,
+    pub friends: u32,
+    pub chats: u32,
+    pub joined_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountSummary {
+    pub account_id: AccountId,
+    pub profile: Option<Profile>,
+    /// Time the account registered, for "member since" displays
+    pub joined_at_ms: u64,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Message {
+    /// Unique and increasing within a chat, assigned when the message is sent or, for
+    /// baseline messages, when they are merged by `recanonicalize_chats`
+    pub id: u64,
     pub author: AccountId,
     pub content: String,
     pub created_at_ms: u64,
+    pub edited_at_ms: Option<u64>,
+    pub quote: Option<Quote>,
+    /// Base64 ed25519 signature of `content`, stored as-is for off-chain verification
+    pub signature: Option<String>,
+    /// The `ed25519:`-prefixed base58 public key `signature` was made with
+    pub signer_public_key: Option<String>,
+    /// Index, in the same chat, of the message this one replies to
+    pub reply_to: Option<u32>,
+    /// When set, the message is hidden from reads until this timestamp, in milliseconds
+    pub send_at_ms: Option<u64>,
+    /// When set, `content` is a ciphertext encrypted by the client
+    pub encryption: Option<EncryptionMeta>,
+    pub kind: MessageKind,
+    pub priority: Priority,
+    /// When set, the message is hidden from reads from this timestamp, in milliseconds
+    pub expires_at_ms: Option<u64>,
+    /// Whether the author deleted the message, in which case its content is empty
+    pub deleted: bool,
+}
+
+/// Who a deleted message disappears for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DeleteScope {
+    /// Only the caller, who can be either participant
+    ForMe,
+    /// Both participants; only the author can, within `DELETE_FOR_EVERYONE_WINDOW_MS`
+    ForEveryone,
+}
+
+/// What a `Message` holds besides its text `content`.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, PartialEq, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MessageKind {
+    #[default]
+    Text,
+    /// A poll whose question is the message content
+    Poll { options: Vec<String> },
+    /// Posted by the contract itself, e.g. the welcome message
+    System,
+}
+
+/// How prominently clients should surface a `Message`.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl Message {
+    /// A plain text message by `author`, created now.
+    fn new(author: AccountId, content: String) -> Self {
+        Self {
+            id: 0,
+            author,
+            content,
+            created_at_ms: env::block_timestamp_ms(),
+            edited_at_ms: None,
+            quote: None,
+            signature: None,
+            signer_public_key: None,
+            reply_to: None,
+            send_at_ms: None,
+            encryption: None,
+            kind: MessageKind::Text,
+            priority: Priority::Normal,
+            expires_at_ms: None,
+            deleted: false,
+        }
+    }
+
+    /// Whether the message is visible, i.e. neither scheduled for later nor expired.
+    fn is_visible(&self) -> bool {
+        let now = env::block_timestamp_ms();
+
+        self.send_at_ms.is_none_or(|send_at_ms| send_at_ms <= now)
+            && self
+                .expires_at_ms
+                .is_none_or(|expires_at_ms| now < expires_at_ms)
+    }
+}
+
+/// A message along with the chat it belongs to, for indexers.
+#[derive(BorshSerialize, Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessageView<'a> {
+    pub chat_id: Base58CryptoHash,
+    pub index: u32,
+    pub participants: &'a (AccountId, AccountId),
+    pub message: &'a Message,
+}
+
+/// A message found by `search_all_messages`, along with the chat it belongs to.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SearchMatch<'a> {
+    pub partner_id: &'a AccountId,
+    pub index: u32,
+    pub message: &'a Message,
+}
+
+/// A group chat between its members, administered by its creator.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Group {
+    pub name: String,
+    pub admin_id: AccountId,
+    pub members: UnorderedSet<AccountId>,
+    pub messages: Vector<Message>,
+    pub created_at_ms: u64,
+    /// Members that can not post until the given timestamp, in milliseconds
+    pub muted_until_ms: LookupMap<AccountId, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupView {
+    pub group_id: u64,
+    pub name: String,
+    pub admin_id: AccountId,
+    pub members_count: u32,
+    pub messages_count: u32,
+    pub created_at_ms: u64,
+}
+
+/// A chat with at least one participant that is no longer a user.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OrphanChat<'a> {
+    pub chat_id: Base58CryptoHash,
+    pub participants: &'a (AccountId, AccountId),
+    pub missing: Vec<&'a AccountId>,
+}
+
+/// Results of a filtering view, which stops reading after `MAX_SCAN` entries.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ScanResult<T> {
+    pub items: Vec<T>,
+    /// Whether the scan hit `MAX_SCAN` before the end, so more matches may exist
+    pub truncated: bool,
+}
+
+impl<T> ScanResult<T> {
+    /// Collects, after skipping `offset` of them, up to `limit` entries matching `keep`
+    /// among at most `MAX_SCAN` entries of `entries`.
+    fn scan(
+        entries: impl Iterator<Item = T>,
+        mut keep: impl FnMut(&T) -> bool,
+        offset: usize,
+        limit: usize,
+    ) -> Self {
+        let mut items = Vec::new();
+        let mut skipped = 0;
+
+        for (scanned, entry) in entries.enumerate() {
+            if items.len() >= limit {
+                break;
+            }
+            if scanned >= MAX_SCAN {
+                return Self {
+                    items,
+                    truncated: true,
+                };
+            }
+            if !keep(&entry) {
+                continue;
+            }
+
+            if skipped < offset {
+                skipped += 1;
+            } else {
+                items.push(entry);
+            }
+        }
+
+        Self {
+            items,
+            truncated: false,
+        }
+    }
+}
+
+/// Everything about an account's relationships, for rendering on app open.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SocialState<'a> {
+    pub friends: Vec<&'a AccountId>,
+    pub incoming: Vec<&'a AccountId>,
+    pub outgoing: Vec<&'a AccountId>,
+    pub blocked: Vec<&'a AccountId>,
+}
+
+/// A page of messages, oldest first, along with the cursor to fetch the next one.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MessagesPage<'a> {
+    pub messages: Vec<&'a Message>,
+    /// The `after_id` to pass for the next page, or `None` if there are no more messages
+    pub next_cursor: Option<u64>,
+}
+
+/// A page of an offset-paginated view, along with the size of the whole collection.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Number of entries stored, so that an empty page past the end can be told apart
+    /// from an empty collection
+    pub total: u32,
+}
+
+/// A snapshot of another message embedded in a `Message`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Quote {
+    pub author: AccountId,
+    pub content: String,
+}
+
+/// How a client encrypted the content of a `Message`, so that the receiver can decrypt it.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EncryptionMeta {
+    pub algorithm: String,
+    pub nonce: String,
+}
+
+/// Who is allowed to send messages to a user.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MessagePolicy {
+    /// Only friends can send messages
+    #[default]
+    FriendsOnly,
+    /// Any user can send messages
+    Anyone,
+}
+
+/// Restrictions on the accounts that can register, on top of NEAR's account id rules.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountPolicy {
+    /// Whether 64 character hex implicit accounts can register
+    pub allow_implicit: bool,
+    /// When set, only sub-accounts of this account can register
+    pub required_parent: Option<AccountId>,
+}
+
+impl Default for AccountPolicy {
+    fn default() -> Self {
+        Self {
+            allow_implicit: true,
+            required_parent: None,
+        }
+    }
+}
+
+/// The kinds of events a user wants clients to push notifications for.
+///
+/// The contract only stores these; clients read them to decide what to push.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NotificationPrefs {
+    pub new_message: bool,
+    pub friend_request: bool,
+    pub reactions: bool,
+}
+
+impl Default for NotificationPrefs {
+    fn default() -> Self {
+        Self {
+            new_message: true,
+            friend_request: true,
+            reactions: true,
+        }
+    }
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, Default, Clone, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Profile {
+    pub display_name: String,
+    pub bio: Option<String>,
+    #[serde(default)]
+    pub allow_messages_from: MessagePolicy,
+}
+
+/// NEP-297 events logged by the contract.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A moderator removed a user and its footprint.
+    UserForceRemoved {
+        account_id: &'a AccountId,
+        moderator_id: &'a AccountId,
+    },
+    /// A message was appended to a chat, resolvable with `resolve_message`.
+    MessageSent {
+        chat_id: Base58CryptoHash,
+        index: u32,
+        author: &'a AccountId,
+    },
+    /// An account reacted to a message.
+    ReactionAdded {
+        chat_id: Base58CryptoHash,
+        index: u32,
+        emoji: &'a str,
+        account_id: &'a AccountId,
+    },
+    /// An account removed its reaction to a message.
+    ReactionRemoved {
+        chat_id: Base58CryptoHash,
+        index: u32,
+        emoji: &'a str,
+        account_id: &'a AccountId,
+    },
+}
+
+impl Event<'_> {
+    fn emit(&self) {
+        #[derive(Serialize)]
+        #[serde(crate = "near_sdk::serde")]
+        struct EventLog<'a, 'b> {
+            standard: &'static str,
+            version: &'static str,
+            #[serde(flatten)]
+            event: &'a Event<'b>,
+        }
+
+        let log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_VERSION,
+            event: self,
+        };
+
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            near_sdk::serde_json::to_string(&log)
+                .unwrap_or_else(|_| env::panic_str("Failed to serialize the event."))
+        ));
+    }
+}
+
+/// Minimal NEP-177 token metadata sent along with a membership badge mint.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub issued_at: Option<String>,
+}
+
+/// The subset of the NFT standard used to mint membership badges.
+///
+/// The minter is expected to make the badge non-transferable.
+#[ext_contract(ext_membership_minter)]
+pub trait MembershipMinter {
+    fn nft_mint(&mut self, token_id: String, receiver_id: AccountId, token_metadata: TokenMetadata);
+}
+
+/// The subset of the fungible token standard used to check premium balances.
+#[ext_contract(ext_premium_token)]
+pub trait PremiumToken {
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
+
+/// The subset of the fungible token standard used to withdraw credits.
+#[ext_contract(ext_credit_token)]
+pub trait CreditToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
 }
 
 #[near_bindgen]
 impl Contract {
     #[init]
-    pub fn new() -> Self {
+    pub fn new(salt: String) -> Self {
+        // The `#[init]` wrapper already refuses to overwrite existing state, checking
+        // here as well keeps that guarantee covered by unit tests.
+        require!(
+            !env::state_exists(),
+            "The contract has already been initialized"
+        );
+
+        Self::internal_new(salt, env::predecessor_account_id())
+    }
+
+    /// Upgrades the state of the baseline contract, which only had `users`, `messages`
+    /// and `friends`, and makes `owner_id` the owner.
+    ///
+    /// Users are kept. Baseline chats stay under their legacy ids until they are
+    /// merged with `recanonicalize_chats`, which also restores the friendship of each
+    /// pair: baseline friendships can not be listed, so `friends` starts over under a
+    /// new prefix. Accounts registered before the migration are not in `sorted_users`.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(salt: String, owner_id: AccountId) -> Self {
+        let legacy: LegacyContract =
+            env::state_read().unwrap_or_else(|| env::panic_str("The contract is not initialized."));
+
+        Self {
+            users: legacy.users,
+            friends: LookupMap::new(StorageKey::MigratedFriends),
+            next_message_id: LEGACY_MESSAGE_IDS,
+            ..Self::internal_new(salt, owner_id)
+        }
+    }
+
+    fn internal_new(salt: String, owner_id: AccountId) -> Self {
         Self {
             users: UnorderedSet::new(StorageKey::Users),
             messages: LookupMap::new(StorageKey::Messages),
             friends: LookupMap::new(StorageKey::Friends),
+            chat_participants: LookupMap::new(StorageKey::ChatParticipants),
+            profiles: LookupMap::new(StorageKey::Profiles),
+            read_receipts: LookupMap::new(StorageKey::ReadReceipts),
+            chat_partners: LookupMap::new(StorageKey::ChatPartners),
+            recovery_delegates: LookupMap::new(StorageKey::RecoveryDelegates),
+            pending_recoveries: LookupMap::new(StorageKey::PendingRecoveries),
+            frozen_accounts: LookupSet::new(StorageKey::FrozenAccounts),
+            messages_sent: LookupMap::new(StorageKey::MessagesSent),
+            joined_at_ms: LookupMap::new(StorageKey::JoinedAt),
+            incoming_requests: LookupMap::new(StorageKey::IncomingRequests),
+            outgoing_requests: LookupMap::new(StorageKey::OutgoingRequests),
+            moderators: UnorderedSet::new(StorageKey::Moderators),
+            auto_accept: LookupMap::new(StorageKey::AutoAccept),
+            notification_prefs: LookupMap::new(StorageKey::NotificationPrefs),
+            verified: UnorderedSet::new(StorageKey::Verified),
+            relayers: UnorderedSet::new(StorageKey::Relayers),
+            relay_keys: LookupMap::new(StorageKey::RelayKeys),
+            relay_nonces: LookupMap::new(StorageKey::RelayNonces),
+            owner_id,
+            membership_minter_id: None,
+            membership_badge_enabled: false,
+            next_message_id: 0,
+            require_friendship_to_message: true,
+            groups: LookupMap::new(StorageKey::Groups),
+            next_group_id: 0,
+            premium_token_id: None,
+            premium_min_balance: 0,
+            credit_token_id: None,
+            balances: LookupMap::new(StorageKey::Balances),
+            total_credits: 0,
+            message_repeats: LookupMap::new(StorageKey::MessageRepeats),
+            max_repeated_messages: DEFAULT_MAX_REPEATED_MESSAGES,
+            max_users: u32::MAX,
+            max_group_members: DEFAULT_MAX_GROUP_MEMBERS,
+            reactions: LookupMap::new(StorageKey::Reactions),
+            salt,
+            usernames: LookupMap::new(StorageKey::Usernames),
+            username_of_account: LookupMap::new(StorageKey::UsernameOfAccount),
+            message_tips: LookupMap::new(StorageKey::MessageTips),
+            allowed_reactions: UnorderedSet::new(StorageKey::AllowedReactions),
+            friend_counts: LookupMap::new(StorageKey::FriendCounts),
+            encrypted_chats: LookupSet::new(StorageKey::EncryptedChats),
+            blocked_users: LookupMap::new(StorageKey::BlockedUsers),
+            drafts: LookupMap::new(StorageKey::Drafts),
+            poll_votes: LookupMap::new(StorageKey::PollVotes),
+            poll_tallies: LookupMap::new(StorageKey::PollTallies),
+            storage_cost_override: None,
+            banned_substrings: Vector::new(StorageKey::BannedSubstrings),
+            total_messages: 0,
+            total_friendships: 0,
+            deleted_at_ms: LookupMap::new(StorageKey::DeletedAt),
+            reregistration_cooldown_ms: 0,
+            reports: Vector::new(StorageKey::Reports),
+            friend_edges: UnorderedSet::new(StorageKey::FriendEdges),
+            last_active_ms: LookupMap::new(StorageKey::LastActive),
+            friend_labels: LookupMap::new(StorageKey::FriendLabels),
+            deleted_messages: LookupMap::new(StorageKey::DeletedMessages),
+            chat_subjects: LookupMap::new(StorageKey::ChatSubjects),
+            chat_ids: UnorderedSet::new(StorageKey::ChatIds),
+            default_message_ttl_ms: None,
+            incoming_request_counts: LookupMap::new(StorageKey::IncomingRequestCounts),
+            hidden_messages: LookupMap::new(StorageKey::HiddenMessages),
+            account_policy: AccountPolicy::default(),
+            sorted_users: TreeMap::new(StorageKey::SortedUsers),
+            reactions_public: true,
+            welcome_message: String::new(),
+            edit_window_ms: DEFAULT_EDIT_WINDOW_MS,
+            pending_owner_id: None,
+            chat_presence: LookupMap::new(StorageKey::ChatPresence),
+            client_nonces: LookupMap::new(StorageKey::ClientNonces),
+            group_memberships: LookupMap::new(StorageKey::GroupMemberships),
+            merged_legacy_chats: LookupSet::new(StorageKey::MergedLegacyChats),
+            next_legacy_message_id: 0,
         }
     }
 
-    pub fn create_account(&mut self) -> bool {
+    /// Registers the caller as a user.
+    ///
+    /// When membership badges are enabled, the attached deposit is forwarded to the
+    /// minter and the user is only registered once the mint succeeds.
+    #[payable]
+    pub fn create_account(&mut self) -> PromiseOrValue<bool> {
         let user_id = env::predecessor_account_id();
-        self.users.insert(user_id)
-    }
 
-    pub fn add_friend(&mut self, friend_id: AccountId) {
-        let user_id = env::predecessor_account_id();
+        if !self.users.contains(&user_id) {
+            self.validate_account(&user_id);
+        }
 
         require!(
-            self.users.contains(&user_id),
-            "You must be a user to add a friend."
+            self.users.contains(&user_id) || self.users.len() < self.max_users,
+            "The maximum number of users has been reached."
         );
 
         require!(
-            self.users.contains(&friend_id),
-            "Your friend must be a user."
+            self.deleted_at_ms
+                .get(&user_id)
+                .is_none_or(|deleted_at_ms| {
+                    env::block_timestamp_ms()
+                        >= deleted_at_ms.saturating_add(self.reregistration_cooldown_ms)
+                }),
+            "This account was deleted recently and can not register again yet."
         );
 
-        require!(user_id != friend_id, "You cannot add yourself as friend.");
-
-        // let is_friend_added_to_user =
-        let friends = self.friends.entry(user_id.clone()).or_insert_with(|| {
-            LookupMap::new(StorageKey::FriendOfUser {
-                user_id: user_id.clone(),
-            })
-        });
-        friends.insert(friend_id.clone(), true);
-        // .unwrap_or_else(|| env::panic_str("Friend not added to User."));
+        let minter_id = match (&self.membership_minter_id, self.membership_badge_enabled) {
+            (Some(minter_id), true) if !self.users.contains(&user_id) => minter_id.clone(),
+            _ => return PromiseOrValue::Value(self.internal_register(user_id)),
+        };
 
-        // let is_user_added_to_friend =
-        let friends = self.friends.entry(friend_id.clone()).or_insert_with(|| {
-            LookupMap::new(StorageKey::FriendOfUser {
-                user_id: friend_id.clone(),
-            })
-        });
-        friends.insert(user_id, true);
-        // .unwrap_or_else(|| env::panic_str("User not added to Friend."));
+        let deposit = env::attached_deposit();
+        let token_metadata = TokenMetadata {
+            title: Some("Chat membership".to_string()),
+            description: Some(format!("Membership badge of {}", user_id)),
+            issued_at: Some(env::block_timestamp_ms().to_string()),
+        };
 
-        // is_friend_added_to_user && is_user_added_to_friend
+        ext_membership_minter::ext(minter_id)
+            .with_attached_deposit(deposit)
+            .with_static_gas(GAS_FOR_NFT_MINT)
+            .nft_mint(user_id.to_string(), user_id.clone(), token_metadata)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_MEMBERSHIP_MINTED)
+                    .on_membership_minted(user_id, U128(deposit)),
+            )
+            .into()
     }
 
-    pub fn send_message(&mut self, receiver_id: AccountId, message_content: String) -> CryptoHash {
-        let user_id = env::predecessor_account_id();
+    /// Registers the given accounts on their behalf, e.g. for migrations or airdrops.
+    ///
+    /// Already registered accounts are skipped. Returns the number of accounts registered.
+    pub fn admin_register_accounts(&mut self, account_ids: Vec<AccountId>) -> u32 {
+        self.assert_owner();
 
         require!(
-            self.users.contains(&user_id),
-            "You must be a user to send a message."
+            account_ids.len() <= MAX_REGISTER_BATCH,
+            format!(
+                "Cannot register more than {} accounts at once.",
+                MAX_REGISTER_BATCH
+            )
         );
 
-        require!(
-            self.users.contains(&receiver_id),
-            "The receiver must be a user to receive a message."
-        );
+        let mut registered = 0;
+        for account_id in account_ids {
+            if self.users.contains(&account_id) {
+                continue;
+            }
 
-        let is_valid_friend = self
-            .friends
-            .get(&user_id)
-            .unwrap_or_else(|| env::panic_str("You do not have any friend."))
-            .contains_key(&receiver_id);
+            require!(
+                self.users.len() < self.max_users,
+                "The maximum number of users has been reached."
+            );
+
+            if self.internal_register(account_id) {
+                registered += 1;
+            }
+        }
+
+        registered
+    }
+
+    /// Removes up to `limit` users, with their friends and chats, e.g. before redeploying
+    /// on testnet. Returns the number of users left; call again until it is zero.
+    ///
+    /// `confirm` must be "reset " followed by the contract account id. Groups and
+    /// contract settings are kept, and the removed users can register again at once.
+    #[payable]
+    pub fn admin_reset(&mut self, confirm: String, limit: Option<u32>) -> u32 {
+        assert_one_yocto();
+        self.assert_owner();
 
         require!(
-            is_valid_friend,
-            "You are not friends with the given receiver."
+            confirm == format!("reset {}", env::current_account_id()),
+            "The confirmation does not match."
         );
 
-        require!(!message_content.is_empty(), "The message can not be empty.");
+        let limit = limit.unwrap_or(MAX_RESET_BATCH);
+        require!(
+            limit <= MAX_RESET_BATCH,
+            format!("Cannot remove more than {} users at once.", MAX_RESET_BATCH)
+        );
 
-        let chat_id: CryptoHash = self.get_chat_id(user_id.clone(), receiver_id);
+        for _ in 0..limit {
+            let Some(user_id) = self.users.iter().next().cloned() else {
+                break;
+            };
 
-        let messages = self
-            .messages
-            .entry(chat_id)
-            .or_insert_with(|| Vector::new(StorageKey::Message));
+            let is_done = self.internal_remove_user(&user_id, DEFAULT_REMOVAL_LIMIT);
+            self.deleted_at_ms.remove(&user_id);
+            if !is_done {
+                // Keep the user listed so that the next call finishes the cleanup.
+                self.users.insert(user_id.clone());
+                self.sorted_users.insert(&user_id, &());
+                break;
+            }
+        }
 
-        let message = Message {
-            author: user_id,
-            content: message_content,
-            created_at_ms: env::block_timestamp_ms(),
-        };
+        self.users.len()
+    }
 
-        messages.push(message);
+    /// Completes a registration started by `create_account` once the badge is minted.
+    ///
+    /// If the mint failed, or other registrations filled up `max_users` or registered
+    /// the user while it was in flight, the user is not registered and the deposit is
+    /// refunded.
+    #[private]
+    pub fn on_membership_minted(&mut self, user_id: AccountId, deposit: U128) -> bool {
+        let has_room = self.users.contains(&user_id) || self.users.len() < self.max_users;
+        if is_promise_success() && has_room && self.internal_register(user_id.clone()) {
+            return true;
+        }
 
-        chat_id
-    }
+        if deposit.0 > 0 {
+            Promise::new(user_id).transfer(deposit.0);
+        }
 
-    pub fn get_chat_id(&self, user_id: AccountId, receiver_id: AccountId) -> CryptoHash {
-        self.calculate_hash(user_id.as_str(), receiver_id.as_str())
+        false
     }
 
-    pub fn get_messages(
-        &self,
-        user_id: AccountId,
-        receiver_id: AccountId,
-        limit: Option<u32>,
-        offset: Option<u32>,
-    ) -> Vec<&Message> {
-        let chat_id: CryptoHash = self.get_chat_id(user_id, receiver_id);
+    #[payable]
+    pub fn set_owner(&mut self, owner_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
 
-        self.messages
-            .get(&chat_id)
-            .unwrap_or_else(|| env::panic_str("The user does not have any messages."))
-            .iter()
-            .rev()
-            .skip(offset.unwrap_or(0) as usize)
-            .take(limit.unwrap_or(10) as usize)
-            .collect::<Vec<&Message>>()
+        self.owner_id = owner_id;
+        self.pending_owner_id = None;
     }
 
-    pub fn get_users(&self, limit: Option<u32>, offset: Option<u32>) -> Vec<&AccountId> {
-        self.users
-            .iter()
-            .rev()
-            .skip(offset.unwrap_or(0) as usize)
-            .take(limit.unwrap_or(10) as usize)
-            .collect()
-    }
+    /// Offers the ownership to `owner_id`, which only takes it with `accept_ownership`.
+    ///
+    /// Unlike `set_owner`, a mistyped or inaccessible account can not end up owning
+    /// the contract.
+    #[payable]
+    pub fn propose_owner(&mut self, owner_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
 
-    pub fn get_users_length(&self) -> u32 {
-        self.users.len()
+        self.pending_owner_id = Some(owner_id);
     }
 
-    fn calculate_hash(&self, a: &str, b: &str) -> CryptoHash {
-        let concatenated_string = format!("{}{}", a, b);
+    #[payable]
+    pub fn accept_ownership(&mut self) {
+        assert_one_yocto();
+        let caller_id = env::predecessor_account_id();
 
-        let value_hash = env::keccak256(concatenated_string.as_bytes());
-        let mut res = CryptoHash::default();
-        res.copy_from_slice(&value_hash);
+        require!(
+            self.pending_owner_id.as_ref() == Some(&caller_id),
+            "Only the proposed owner can accept the ownership."
+        );
 
-        res
+        self.owner_id = caller_id;
+        self.pending_owner_id = None;
     }
-}
 
-#[allow(dead_code, unused)]
-#[cfg(all(test, not(target_arch = "wasm32")))]
-mod tests {
-    use near_sdk::{
-        test_utils::{accounts, VMContextBuilder},
-        testing_env, AccountId, ONE_NEAR,
-    };
+    #[payable]
+    pub fn cancel_ownership_transfer(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
 
-    use crate::Contract;
+        self.pending_owner_id = None;
+    }
 
-    fn contract_account() -> AccountId {
-        "contract".parse::<AccountId>().unwrap()
+    pub fn get_pending_owner(&self) -> Option<&AccountId> {
+        self.pending_owner_id.as_ref()
     }
 
-    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+    pub fn set_membership_minter(&mut self, minter_id: Option<AccountId>, enabled: bool) {
+        self.assert_owner();
+
+        require!(
+            !enabled || minter_id.is_some(),
+            "A minter is required to enable membership badges."
+        );
+
+        self.membership_minter_id = minter_id;
+        self.membership_badge_enabled = enabled;
+    }
+
+    pub fn add_friend(&mut self, friend_id: AccountId) -> FriendAddResult {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to add a friend."
+        );
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            self.users.contains(&friend_id),
+            "Your friend must be a user."
+        );
+
+        require!(user_id != friend_id, "You cannot add yourself as friend.");
+
+        require!(
+            friend_id != env::current_account_id(),
+            "You cannot add the contract as friend."
+        );
+
+        // Skip the writes when the friendship already exists.
+        if self.internal_are_friends(&user_id, &friend_id) {
+            return FriendAddResult::AlreadyFriends;
+        }
+
+        self.internal_add_friendship(user_id, friend_id);
+
+        FriendAddResult::Added
+    }
+
+    pub fn remove_friend(&mut self, friend_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            self.internal_are_friends(&user_id, &friend_id),
+            "You are not friends with this account."
+        );
+
+        self.internal_remove_friendship(&user_id, &friend_id);
+
+        // Per-chat metadata of both participants would otherwise linger.
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &friend_id);
+        for account_id in [&user_id, &friend_id] {
+            if let Some(receipts) = self.read_receipts.get_mut(account_id) {
+                receipts.remove(&chat_id);
+            }
+            if let Some(drafts) = self.drafts.get_mut(account_id) {
+                drafts.remove(&chat_id);
+            }
+        }
+    }
+
+    /// Replaces the labels the caller gave `friend_id`; an empty list clears them.
+    pub fn set_friend_labels(&mut self, friend_id: AccountId, labels: Vec<String>) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            self.internal_are_friends(&user_id, &friend_id),
+            "You are not friends with this account."
+        );
+
+        require!(
+            labels.len() <= MAX_FRIEND_LABELS,
+            format!(
+                "A friend can not have more than {} labels.",
+                MAX_FRIEND_LABELS
+            )
+        );
+
+        require!(
+            labels
+                .iter()
+                .all(|label| !label.is_empty() && label.len() <= MAX_FRIEND_LABEL_LEN),
+            format!(
+                "Labels must be between 1 and {} bytes long.",
+                MAX_FRIEND_LABEL_LEN
+            )
+        );
+
+        let friend_labels = self
+            .friend_labels
+            .entry(user_id.clone())
+            .or_insert_with(|| {
+                LookupMap::new(StorageKey::FriendLabelsOfUser {
+                    user_id: user_id.clone(),
+                })
+            });
+
+        if labels.is_empty() {
+            friend_labels.remove(&friend_id);
+        } else {
+            friend_labels.insert(friend_id, labels);
+        }
+    }
+
+    pub fn get_friend_labels(&self, user_id: AccountId, friend_id: AccountId) -> Vec<&String> {
+        self.friend_labels
+            .get(&user_id)
+            .and_then(|friend_labels| friend_labels.get(&friend_id))
+            .map(|labels| labels.iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the friends `user_id` gave `label`, among the first `MAX_SCAN` friends.
+    pub fn get_friends_by_label(
+        &self,
+        user_id: AccountId,
+        label: String,
+    ) -> ScanResult<&AccountId> {
+        let (Some(friends), Some(friend_labels)) =
+            (self.friends.get(&user_id), self.friend_labels.get(&user_id))
+        else {
+            return ScanResult {
+                items: Vec::new(),
+                truncated: false,
+            };
+        };
+
+        ScanResult::scan(
+            friends.iter(),
+            |friend_id| {
+                friend_labels
+                    .get(*friend_id)
+                    .is_some_and(|labels| labels.contains(&label))
+            },
+            0,
+            usize::MAX,
+        )
+    }
+
+    pub fn are_friends(&self, user_id: AccountId, friend_id: AccountId) -> bool {
+        self.internal_are_friends(&user_id, &friend_id)
+    }
+
+    /// Returns the number of friends of `account_id`, without iterating them.
+    pub fn get_degree(&self, account_id: AccountId) -> u32 {
+        self.friend_counts.get(&account_id).copied().unwrap_or(0)
+    }
+
+    /// Returns the friends of `user_id` sorted by account id.
+    ///
+    /// Sorting makes `offset` pagination stable across insertions and removals,
+    /// at the cost of reading the whole friend list on every call.
+    pub fn get_friends_paginated_sorted(
+        &self,
+        user_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&AccountId> {
+        let mut friends: Vec<&AccountId> = match self.friends.get(&user_id) {
+            Some(friends) => friends.iter().collect(),
+            None => return Vec::new(),
+        };
+        friends.sort();
+
+        friends
+            .into_iter()
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(10) as usize)
+            .collect()
+    }
+
+    /// Returns the friends of `user_id` along with their profile, for friend lists.
+    ///
+    /// At most `MAX_USERS_PAGE` friends are returned.
+    pub fn get_friends_with_profiles(
+        &self,
+        user_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<(&AccountId, Option<&Profile>)> {
+        self.friends
+            .get(&user_id)
+            .map(|friends| {
+                friends
+                    .iter()
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(limit.unwrap_or(10).min(MAX_USERS_PAGE) as usize)
+                    .map(|friend_id| (friend_id, self.profiles.get(friend_id)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Asks `target_id` to become friends; the friendship starts once they accept.
+    pub fn send_friend_request(&mut self, target_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to send a friend request."
+        );
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            self.users.contains(&target_id),
+            "The friend request target must be a user."
+        );
+
+        require!(
+            user_id != target_id,
+            "You cannot send a friend request to yourself."
+        );
+
+        require!(
+            target_id != env::current_account_id(),
+            "You cannot send a friend request to the contract."
+        );
+
+        require!(
+            !self.internal_are_friends(&user_id, &target_id),
+            "You are already friends."
+        );
+
+        require!(
+            !self.internal_has_request(&target_id, &user_id),
+            "The target has already sent you a friend request."
+        );
+
+        require!(
+            !self.internal_is_blocked(&target_id, &user_id),
+            "You have been blocked by this user."
+        );
+
+        let is_auto_accepted = self
+            .auto_accept
+            .get(&target_id)
+            .is_some_and(|accepted| accepted.contains(&user_id));
+        if is_auto_accepted {
+            // A request sent before the allowlisting would otherwise stay pending.
+            self.internal_remove_request(&user_id, &target_id);
+            self.internal_remove_request(&target_id, &user_id);
+            self.internal_add_friendship(target_id, user_id);
+            return;
+        }
+
+        let is_new_request = self
+            .outgoing_requests
+            .entry(user_id.clone())
+            .or_insert_with(|| {
+                UnorderedSet::new(StorageKey::OutgoingRequestsOfUser {
+                    user_id: user_id.clone(),
+                })
+            })
+            .insert(target_id.clone());
+
+        require!(is_new_request, "The friend request is already pending.");
+
+        self.incoming_requests
+            .entry(target_id.clone())
+            .or_insert_with(|| {
+                UnorderedSet::new(StorageKey::IncomingRequestsOfUser {
+                    user_id: target_id.clone(),
+                })
+            })
+            .insert(user_id);
+        *self.incoming_request_counts.entry(target_id).or_insert(0) += 1;
+    }
+
+    pub fn accept_friend_request(&mut self, requester_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            self.internal_remove_request(&requester_id, &user_id),
+            "There is no pending friend request from this account."
+        );
+
+        self.internal_add_friendship(user_id, requester_id);
+    }
+
+    /// Makes friend requests from `account_id` turn into a friendship at once, without
+    /// waiting for the caller to accept them.
+    pub fn add_auto_accept(&mut self, account_id: AccountId) -> bool {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to auto-accept friend requests."
+        );
+
+        self.auto_accept
+            .entry(user_id.clone())
+            .or_insert_with(|| UnorderedSet::new(StorageKey::AutoAcceptOfUser { user_id }))
+            .insert(account_id)
+    }
+
+    pub fn remove_auto_accept(&mut self, account_id: AccountId) -> bool {
+        let user_id = env::predecessor_account_id();
+
+        self.auto_accept
+            .get_mut(&user_id)
+            .is_some_and(|accepted| accepted.remove(&account_id))
+    }
+
+    /// Returns up to `MAX_SCAN` of the accounts `user_id` auto-accepts friend requests from.
+    pub fn get_auto_accept(&self, user_id: AccountId) -> Vec<&AccountId> {
+        self.auto_accept
+            .get(&user_id)
+            .map(|accepted| accepted.iter().take(MAX_SCAN).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_notification_prefs(&mut self, prefs: NotificationPrefs) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to set notification preferences."
+        );
+
+        self.notification_prefs.insert(user_id, prefs);
+    }
+
+    /// Returns the notification preferences of `account_id`, with every notification
+    /// enabled for users that never set them.
+    pub fn get_notification_prefs(&self, account_id: AccountId) -> NotificationPrefs {
+        self.notification_prefs
+            .get(&account_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Declines a pending friend request `requester_id` sent to the caller.
+    pub fn reject_friend_request(&mut self, requester_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.internal_remove_request(&requester_id, &user_id),
+            "There is no pending friend request from this account."
+        );
+    }
+
+    /// Withdraws a friend request the caller sent to `target_id` that is still pending.
+    pub fn cancel_friend_request(&mut self, target_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.internal_remove_request(&user_id, &target_id),
+            "There is no pending friend request to this account."
+        );
+    }
+
+    /// Prevents `account_id` from messaging the caller or sending it friend requests.
+    #[payable]
+    pub fn block_user(&mut self, account_id: AccountId) -> bool {
+        assert_one_yocto();
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        require!(user_id != account_id, "You cannot block yourself.");
+
+        self.blocked_users
+            .entry(user_id.clone())
+            .or_insert_with(|| UnorderedSet::new(StorageKey::BlockedUsersOfUser { user_id }))
+            .insert(account_id)
+    }
+
+    #[payable]
+    pub fn unblock_user(&mut self, account_id: AccountId) -> bool {
+        assert_one_yocto();
+        let user_id = env::predecessor_account_id();
+
+        self.blocked_users
+            .get_mut(&user_id)
+            .is_some_and(|blocked| blocked.remove(&account_id))
+    }
+
+    /// Returns the friends, pending requests and blocked accounts of `account_id`.
+    ///
+    /// Each list holds at most `MAX_SOCIAL_STATE_LIST` accounts.
+    pub fn get_social_state(&self, account_id: AccountId) -> SocialState<'_> {
+        fn bounded<'a>(accounts: impl Iterator<Item = &'a AccountId>) -> Vec<&'a AccountId> {
+            accounts.take(MAX_SOCIAL_STATE_LIST).collect()
+        }
+
+        SocialState {
+            friends: self
+                .friends
+                .get(&account_id)
+                .map(|friends| bounded(friends.iter()))
+                .unwrap_or_default(),
+            incoming: self
+                .incoming_requests
+                .get(&account_id)
+                .map(|requests| bounded(requests.iter()))
+                .unwrap_or_default(),
+            outgoing: self
+                .outgoing_requests
+                .get(&account_id)
+                .map(|requests| bounded(requests.iter()))
+                .unwrap_or_default(),
+            blocked: self
+                .blocked_users
+                .get(&account_id)
+                .map(|blocked| bounded(blocked.iter()))
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn get_incoming_requests(
+        &self,
+        account_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&AccountId> {
+        self.incoming_requests
+            .get(&account_id)
+            .map(|requests| {
+                requests
+                    .iter()
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(limit.unwrap_or(10) as usize)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of pending friend requests sent to `user_id`, without iterating them.
+    pub fn get_incoming_request_count(&self, user_id: AccountId) -> u32 {
+        self.incoming_request_counts
+            .get(&user_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn get_outgoing_requests(
+        &self,
+        account_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&AccountId> {
+        self.outgoing_requests
+            .get(&account_id)
+            .map(|requests| {
+                requests
+                    .iter()
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(limit.unwrap_or(10) as usize)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sends a message to `receiver_id`.
+    ///
+    /// Retrying with the same `client_nonce` returns where the first message landed
+    /// instead of sending it again, as long as the nonce is among the last
+    /// `MAX_CLIENT_NONCES` ones sent to the chat.
+    pub fn send_message(
+        &mut self,
+        receiver_id: AccountId,
+        message_content: String,
+        expected_count: Option<u32>,
+        client_nonce: Option<String>,
+    ) -> SentMessage {
+        let user_id = env::predecessor_account_id();
+        let chat_id = self.internal_chat_id(&user_id, &receiver_id);
+
+        if let Some(nonce) = &client_nonce {
+            require!(
+                nonce.len() <= MAX_CLIENT_NONCE_LEN,
+                format!(
+                    "The client nonce can not be longer than {} bytes.",
+                    MAX_CLIENT_NONCE_LEN
+                )
+            );
+
+            let sent = self.client_nonces.get(&chat_id).and_then(|nonces| {
+                nonces
+                    .iter()
+                    .find(|entry| entry.author == user_id && entry.nonce == *nonce)
+            });
+            if let Some(entry) = sent {
+                return entry.sent.clone();
+            }
+        }
+
+        // Lets clients that computed indices, e.g. for replies, fail fast when stale.
+        if let Some(expected_count) = expected_count {
+            let count = self
+                .messages
+                .get(&chat_id)
+                .map_or(0, |messages| messages.len());
+
+            require!(
+                count == expected_count,
+                "The chat has changed since it was last read."
+            );
+        }
+
+        let message = Message::new(user_id.clone(), message_content);
+        let sent = self.internal_send_message(receiver_id, message);
+
+        if let Some(nonce) = client_nonce {
+            let nonces = self.client_nonces.entry(chat_id).or_default();
+            if nonces.len() >= MAX_CLIENT_NONCES {
+                nonces.remove(0);
+            }
+            nonces.push(ClientNonce {
+                author: user_id,
+                nonce,
+                sent: sent.clone(),
+            });
+        }
+
+        sent
+    }
+
+    /// Sends a message with the given `priority`, e.g. `High` for important ones.
+    pub fn send_message_with_priority(
+        &mut self,
+        receiver_id: AccountId,
+        message_content: String,
+        priority: Priority,
+    ) -> Base58CryptoHash {
+        let message = Message {
+            priority,
+            ..Message::new(env::predecessor_account_id(), message_content)
+        };
+
+        self.internal_send_message(receiver_id, message).chat_id
+    }
+
+    /// Sends a message replying to the message at `reply_to` in the same chat.
+    pub fn send_reply(
+        &mut self,
+        receiver_id: AccountId,
+        message_content: String,
+        reply_to: u32,
+    ) -> Base58CryptoHash {
+        let user_id = env::predecessor_account_id();
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+
+        require!(
+            self.messages
+                .get(&chat_id)
+                .is_some_and(|messages| reply_to < messages.len()),
+            "The replied message does not exist."
+        );
+
+        let message = Message {
+            reply_to: Some(reply_to),
+            ..Message::new(user_id, message_content)
+        };
+
+        self.internal_send_message(receiver_id, message).chat_id
+    }
+
+    /// Posts a poll with the given options to the caller's chat with `receiver_id`.
+    pub fn send_poll(
+        &mut self,
+        receiver_id: AccountId,
+        question: String,
+        options: Vec<String>,
+    ) -> Base58CryptoHash {
+        require!(
+            (MIN_POLL_OPTIONS..=MAX_POLL_OPTIONS).contains(&options.len()),
+            format!(
+                "A poll must have between {} and {} options.",
+                MIN_POLL_OPTIONS, MAX_POLL_OPTIONS
+            )
+        );
+
+        require!(
+            options
+                .iter()
+                .all(|option| !option.is_empty() && option.len() <= MAX_POLL_OPTION_LEN),
+            format!(
+                "Poll options must be between 1 and {} bytes long.",
+                MAX_POLL_OPTION_LEN
+            )
+        );
+
+        let options_count = options.len();
+        let message = Message {
+            kind: MessageKind::Poll { options },
+            ..Message::new(env::predecessor_account_id(), question)
+        };
+        let sent = self.internal_send_message(receiver_id, message);
+        self.poll_tallies.insert(sent.id, vec![0; options_count]);
+
+        sent.chat_id
+    }
+
+    /// Votes for an option of a poll, replacing the caller's previous vote if any.
+    pub fn vote_poll(&mut self, receiver_id: AccountId, index: u32, option_index: u32) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let message_id = self.internal_message_id(&user_id, &receiver_id, index);
+        let tallies = self
+            .poll_tallies
+            .get_mut(&message_id)
+            .unwrap_or_else(|| env::panic_str("The message is not a poll."));
+
+        require!(
+            (option_index as usize) < tallies.len(),
+            "The poll option does not exist."
+        );
+
+        let previous = self
+            .poll_votes
+            .entry(message_id)
+            .or_insert_with(|| LookupMap::new(StorageKey::PollVotesOfMessage { message_id }))
+            .insert(user_id, option_index);
+
+        if let Some(previous) = previous {
+            tallies[previous as usize] -= 1;
+        }
+        tallies[option_index as usize] += 1;
+    }
+
+    /// Returns the number of votes for each option of a poll.
+    pub fn get_poll_results(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        index: u32,
+    ) -> Option<&Vec<u32>> {
+        let message_id = self.internal_message_id(&user_id, &receiver_id, index);
+        self.poll_tallies.get(&message_id)
+    }
+
+    /// Queues a message that stays hidden from reads until `send_at_ms`.
+    ///
+    /// The message keeps its position in the chat, so it shows up among older messages once due.
+    pub fn schedule_message(
+        &mut self,
+        receiver_id: AccountId,
+        message_content: String,
+        send_at_ms: u64,
+    ) -> Base58CryptoHash {
+        require!(
+            send_at_ms > env::block_timestamp_ms(),
+            "Scheduled messages must be sent in the future."
+        );
+
+        let message = Message {
+            send_at_ms: Some(send_at_ms),
+            ..Message::new(env::predecessor_account_id(), message_content)
+        };
+
+        self.internal_send_message(receiver_id, message).chat_id
+    }
+
+    /// Sends a message whose content was encrypted by the client.
+    pub fn send_encrypted_message(
+        &mut self,
+        receiver_id: AccountId,
+        ciphertext: String,
+        encryption: EncryptionMeta,
+    ) -> Base58CryptoHash {
+        require!(
+            !encryption.algorithm.is_empty() && !encryption.nonce.is_empty(),
+            "The encryption algorithm and nonce can not be empty."
+        );
+
+        let message = Message {
+            encryption: Some(encryption),
+            ..Message::new(env::predecessor_account_id(), ciphertext)
+        };
+
+        self.internal_send_message(receiver_id, message).chat_id
+    }
+
+    /// Requires every later message of the caller's chat with `receiver_id` to be
+    /// encrypted client-side. This can not be undone.
+    pub fn enable_e2e(&mut self, receiver_id: AccountId) -> bool {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to enable encryption."
+        );
+        self.assert_not_frozen(&user_id);
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        self.encrypted_chats.insert(chat_id)
+    }
+
+    /// Sets or, with `None`, clears the subject of a chat the caller participates in.
+    pub fn set_chat_subject(&mut self, chat_id: Base58CryptoHash, subject: Option<String>) {
+        let chat_id: CryptoHash = chat_id.into();
+        self.assert_chat_participant(&chat_id);
+
+        match subject {
+            Some(subject) => {
+                require!(
+                    !subject.is_empty() && subject.len() <= MAX_CHAT_SUBJECT_LEN,
+                    format!(
+                        "The subject must be between 1 and {} bytes long.",
+                        MAX_CHAT_SUBJECT_LEN
+                    )
+                );
+                self.chat_subjects.insert(chat_id, subject);
+            }
+            None => {
+                self.chat_subjects.remove(&chat_id);
+            }
+        }
+    }
+
+    pub fn get_chat_subject(&self, chat_id: Base58CryptoHash) -> Option<&String> {
+        self.chat_subjects.get(&CryptoHash::from(chat_id))
+    }
+
+    pub fn is_e2e_enabled(&self, user_id: AccountId, receiver_id: AccountId) -> bool {
+        self.encrypted_chats
+            .contains(&self.internal_chat_id(&user_id, &receiver_id))
+    }
+
+    /// Sends a message along with the sender's ed25519 signature of its content.
+    ///
+    /// The encodings are validated, but the signature itself is not verified on-chain;
+    /// clients are expected to check it against `signer_public_key`.
+    pub fn send_signed_message(
+        &mut self,
+        receiver_id: AccountId,
+        message_content: String,
+        signature: String,
+        signer_public_key: String,
+    ) -> Base58CryptoHash {
+        let signature_len = near_sdk::base64::decode(&signature).map_or(0, |bytes| bytes.len());
+        require!(
+            signature_len == ED25519_SIGNATURE_LEN,
+            "The signature must be a base64 encoded ed25519 signature."
+        );
+
+        require!(
+            signer_public_key
+                .parse::<PublicKey>()
+                .is_ok_and(|public_key| public_key.curve_type() == CurveType::ED25519),
+            "The signer public key must be an ed25519 public key."
+        );
+
+        let message = Message {
+            signature: Some(signature),
+            signer_public_key: Some(signer_public_key),
+            ..Message::new(env::predecessor_account_id(), message_content)
+        };
+
+        self.internal_send_message(receiver_id, message).chat_id
+    }
+
+    /// Sends a message on behalf of `author`, for relayers paying the gas of their users.
+    ///
+    /// `signature` is the base64 ed25519 signature, made with the key the author set with
+    /// `set_relay_key`, of "{contract_id}:{author}:{receiver_id}:{nonce}:{content}".
+    /// `nonce` must be greater than the author's previous one, so that a signed message
+    /// can only be submitted once.
+    pub fn send_message_as(
+        &mut self,
+        author: AccountId,
+        receiver_id: AccountId,
+        content: String,
+        nonce: u64,
+        signature: String,
+    ) -> SentMessage {
+        require!(
+            self.relayers.contains(&env::predecessor_account_id()),
+            "Only relayers can send messages on behalf of users."
+        );
+
+        require!(
+            self.relay_nonces
+                .get(&author)
+                .is_none_or(|last_nonce| nonce > *last_nonce),
+            "The nonce has already been used."
+        );
+
+        let payload = format!(
+            "{}:{}:{}:{}:{}",
+            env::current_account_id(),
+            author,
+            receiver_id,
+            nonce,
+            content
+        );
+        require!(
+            self.internal_verify_relay_signature(&author, payload.as_bytes(), &signature),
+            "The signature does not match the author's relay key."
+        );
+        self.relay_nonces.insert(author.clone(), nonce);
+
+        self.internal_send_message(receiver_id, Message::new(author, content))
+    }
+
+    /// Sets or, with `None`, removes the ed25519 key relayers' messages on the caller's
+    /// behalf must be signed with.
+    pub fn set_relay_key(&mut self, public_key: Option<PublicKey>) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to set a relay key."
+        );
+
+        match public_key {
+            Some(public_key) => {
+                require!(
+                    public_key.curve_type() == CurveType::ED25519,
+                    "The relay key must be an ed25519 public key."
+                );
+                self.relay_keys.insert(user_id, public_key);
+            }
+            None => {
+                self.relay_keys.remove(&user_id);
+            }
+        }
+    }
+
+    pub fn get_relay_key(&self, account_id: AccountId) -> Option<&PublicKey> {
+        self.relay_keys.get(&account_id)
+    }
+
+    pub fn get_relay_nonce(&self, account_id: AccountId) -> u64 {
+        self.relay_nonces.get(&account_id).copied().unwrap_or(0)
+    }
+
+    /// Sends a message embedding a copy of `quoted_content`.
+    ///
+    /// Unlike a reference, the quote is a snapshot: it is not affected by later
+    /// edits or deletions of the quoted message.
+    pub fn send_quote(
+        &mut self,
+        receiver_id: AccountId,
+        content: String,
+        quoted_content: String,
+        quoted_author: AccountId,
+    ) -> Base58CryptoHash {
+        require!(
+            !quoted_content.is_empty(),
+            "The quoted content can not be empty."
+        );
+
+        require!(
+            quoted_content.len() <= MAX_QUOTE_LEN,
+            format!(
+                "The quoted content can not be longer than {} bytes.",
+                MAX_QUOTE_LEN
+            )
+        );
+
+        let message = Message {
+            quote: Some(Quote {
+                author: quoted_author,
+                content: quoted_content,
+            }),
+            ..Message::new(env::predecessor_account_id(), content)
+        };
+
+        self.internal_send_message(receiver_id, message).chat_id
+    }
+
+    /// Replaces the content of a message the caller authored.
+    pub fn edit_message(&mut self, receiver_id: AccountId, index: u32, new_content: String) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let is_encrypted = self
+            .messages
+            .get(&chat_id)
+            .and_then(|messages| messages.get(index))
+            .is_some_and(|message| message.encryption.is_some());
+        self.assert_valid_content(&chat_id, &new_content, is_encrypted);
+
+        let message = self
+            .messages
+            .get_mut(&chat_id)
+            .and_then(|messages| messages.get_mut(index))
+            .unwrap_or_else(|| env::panic_str("The message does not exist."));
+
+        require!(
+            message.author == user_id,
+            "You can only edit your own messages."
+        );
+
+        require!(!message.deleted, "The message was deleted.");
+
+        require!(
+            env::block_timestamp_ms() < message.created_at_ms.saturating_add(self.edit_window_ms),
+            "The message can no longer be edited."
+        );
+
+        message.content = new_content;
+        message.edited_at_ms = Some(env::block_timestamp_ms());
+    }
+
+    /// Deletes a message of the caller's chat with `receiver_id`, keeping its place in
+    /// the chat.
+    ///
+    /// `ForMe` only hides it from the caller's `get_messages`. `ForEveryone` erases the
+    /// content from the message; audit views can still list it with `include_deleted`,
+    /// and the author can `restore_message` within `RESTORE_WINDOW_MS`.
+    pub fn delete_message(&mut self, receiver_id: AccountId, index: u32, scope: DeleteScope) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let message = self
+            .messages
+            .get_mut(&chat_id)
+            .and_then(|messages| messages.get_mut(index))
+            .unwrap_or_else(|| env::panic_str("The message does not exist."));
+
+        if scope == DeleteScope::ForMe {
+            let message_id = message.id;
+            self.hidden_messages
+                .entry(user_id.clone())
+                .or_insert_with(|| UnorderedSet::new(StorageKey::HiddenMessagesOfUser { user_id }))
+                .insert(message_id);
+            return;
+        }
+
+        require!(
+            message.author == user_id,
+            "You can only delete your own messages."
+        );
+
+        require!(
+            env::block_timestamp_ms() < message.created_at_ms + DELETE_FOR_EVERYONE_WINDOW_MS,
+            "The message can no longer be deleted for everyone."
+        );
+
+        require!(!message.deleted, "The message was deleted.");
+
+        message.deleted = true;
+        let deleted = DeletedContent {
+            content: std::mem::take(&mut message.content),
+            quote: message.quote.take(),
+            deleted_at_ms: env::block_timestamp_ms(),
+        };
+        let message_id = message.id;
+        self.deleted_messages.insert(message_id, deleted);
+    }
+
+    /// Restores one of the caller's deleted messages, within `RESTORE_WINDOW_MS` of the deletion.
+    pub fn restore_message(&mut self, receiver_id: AccountId, index: u32) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let message = self
+            .messages
+            .get_mut(&chat_id)
+            .and_then(|messages| messages.get_mut(index))
+            .unwrap_or_else(|| env::panic_str("The message does not exist."));
+
+        require!(
+            message.author == user_id,
+            "You can only restore your own messages."
+        );
+
+        let restorable = message.deleted
+            && self
+                .deleted_messages
+                .get(&message.id)
+                .is_some_and(|deleted| {
+                    env::block_timestamp_ms() < deleted.deleted_at_ms + RESTORE_WINDOW_MS
+                });
+        require!(restorable, "The message can no longer be restored.");
+
+        let deleted = self.deleted_messages.remove(&message.id).unwrap();
+        message.deleted = false;
+        message.content = deleted.content;
+        message.quote = deleted.quote;
+    }
+
+    /// Permanently erases the content kept for a message deleted more than
+    /// `RESTORE_WINDOW_MS` ago. Anyone can call it; returns whether anything was erased.
+    pub fn purge_deleted_message(&mut self, message_id: u64) -> bool {
+        let expired = self
+            .deleted_messages
+            .get(&message_id)
+            .is_some_and(|deleted| {
+                env::block_timestamp_ms() >= deleted.deleted_at_ms + RESTORE_WINDOW_MS
+            });
+
+        if expired {
+            self.deleted_messages.remove(&message_id);
+        }
+
+        expired
+    }
+
+    /// Reacts with `emoji` to a message of the caller's chat with `receiver_id`.
+    ///
+    /// Returns false if the caller had already reacted with it.
+    pub fn react_to_message(&mut self, receiver_id: AccountId, index: u32, emoji: String) -> bool {
+        let emoji = Self::normalize_reaction(&emoji);
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let message_id = self.internal_message_id(&user_id, &receiver_id, index);
+        if !self.internal_add_reaction(user_id.clone(), message_id, emoji.clone()) {
+            return false;
+        }
+
+        Event::ReactionAdded {
+            chat_id: self.internal_chat_id(&user_id, &receiver_id).into(),
+            index,
+            emoji: &emoji,
+            account_id: &user_id,
+        }
+        .emit();
+
+        true
+    }
+
+    /// Reacts with `emoji` and tips the attached `amount` to the message's author.
+    ///
+    /// The tip is refunded if the transfer fails; the reaction is kept either way.
+    #[payable]
+    pub fn react_with_tip(
+        &mut self,
+        receiver_id: AccountId,
+        index: u32,
+        emoji: String,
+        amount: U128,
+    ) -> Promise {
+        let emoji = Self::normalize_reaction(&emoji);
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        require!(amount.0 > 0, "The tip must be greater than zero.");
+
+        require!(
+            env::attached_deposit() == amount.0,
+            "The attached deposit must match the tip amount."
+        );
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let message = self
+            .messages
+            .get(&chat_id)
+            .and_then(|messages| messages.get(index))
+            .unwrap_or_else(|| env::panic_str("The message does not exist."));
+        let (message_id, author_id) = (message.id, message.author.clone());
+
+        require!(author_id != user_id, "You can not tip your own messages.");
+
+        if self.internal_add_reaction(user_id.clone(), message_id, emoji.clone()) {
+            Event::ReactionAdded {
+                chat_id: chat_id.into(),
+                index,
+                emoji: &emoji,
+                account_id: &user_id,
+            }
+            .emit();
+        }
+
+        Promise::new(author_id).transfer(amount.0).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_ON_TIP_TRANSFERRED)
+                .on_tip_transferred(message_id, user_id, amount),
+        )
+    }
+
+    /// Records a tip once it reached the author, or refunds the tipper if it did not.
+    #[private]
+    pub fn on_tip_transferred(
+        &mut self,
+        message_id: u64,
+        tipper_id: AccountId,
+        amount: U128,
+    ) -> bool {
+        if !is_promise_success() {
+            Promise::new(tipper_id).transfer(amount.0);
+            return false;
+        }
+
+        *self.message_tips.entry(message_id).or_insert(0) += amount.0;
+        true
+    }
+
+    /// Returns the total tips, in yoctoNEAR, the author of a message received for it.
+    pub fn get_message_tips(&self, user_id: AccountId, receiver_id: AccountId, index: u32) -> U128 {
+        let message_id = self.internal_message_id(&user_id, &receiver_id, index);
+        U128(self.message_tips.get(&message_id).copied().unwrap_or(0))
+    }
+
+    /// Removes the caller's own `emoji` reaction; other accounts' reactions are kept.
+    ///
+    /// Returns false if the caller had not reacted with it.
+    pub fn remove_reaction(&mut self, receiver_id: AccountId, index: u32, emoji: String) -> bool {
+        let emoji = Self::normalize_reaction(&emoji);
+        let user_id = env::predecessor_account_id();
+        let message_id = self.internal_message_id(&user_id, &receiver_id, index);
+
+        let reactions = match self.reactions.get_mut(&message_id) {
+            Some(reactions) => reactions,
+            None => return false,
+        };
+
+        let reactors = match reactions.get_mut(&emoji) {
+            Some(reactors) => reactors,
+            None => return false,
+        };
+
+        if !reactors.remove(&user_id) {
+            return false;
+        }
+
+        if reactors.is_empty() {
+            reactions.remove(&emoji);
+        }
+
+        Event::ReactionRemoved {
+            chat_id: self.internal_chat_id(&user_id, &receiver_id).into(),
+            index,
+            emoji: &emoji,
+            account_id: &user_id,
+        }
+        .emit();
+
+        true
+    }
+
+    /// Returns the accounts that reacted with `emoji` to a message, unless the owner
+    /// made reactors private with `set_reactions_public`.
+    pub fn get_reactors(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        index: u32,
+        emoji: String,
+    ) -> Vec<&AccountId> {
+        let emoji = Self::normalize_reaction(&emoji);
+        require!(
+            self.reactions_public,
+            "Reactors are private, only reaction counts are available."
+        );
+
+        let message_id = self.internal_message_id(&user_id, &receiver_id, index);
+
+        self.reactions
+            .get(&message_id)
+            .and_then(|reactions| reactions.get(&emoji))
+            .map(|reactors| reactors.iter().take(MAX_SCAN).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns how many accounts reacted with each emoji to a message.
+    pub fn get_reactions(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        index: u32,
+    ) -> Vec<(&String, u32)> {
+        let message_id = self.internal_message_id(&user_id, &receiver_id, index);
+        self.internal_reaction_counts(message_id)
+    }
+
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.internal_update_profile(|current| *current = profile);
+    }
+
+    /// Updates only the display name, creating a default profile if needed.
+    pub fn set_display_name(&mut self, display_name: String) {
+        self.internal_update_profile(|profile| profile.display_name = display_name);
+    }
+
+    /// Updates only the bio, creating a default profile if needed.
+    pub fn set_bio(&mut self, bio: Option<String>) {
+        self.internal_update_profile(|profile| profile.bio = bio);
+    }
+
+    pub fn get_profile(&self, account_id: AccountId) -> Option<&Profile> {
+        self.profiles.get(&account_id)
+    }
+
+    /// Returns the profiles of up to `MAX_PROFILES_BATCH` accounts, in the given order.
+    pub fn bulk_get_profiles(
+        &self,
+        account_ids: Vec<AccountId>,
+    ) -> Vec<(AccountId, Option<&Profile>)> {
+        require!(
+            account_ids.len() <= MAX_PROFILES_BATCH,
+            format!(
+                "Cannot get more than {} profiles at once.",
+                MAX_PROFILES_BATCH
+            )
+        );
+
+        account_ids
+            .into_iter()
+            .map(|account_id| {
+                let profile = self.profiles.get(&account_id);
+                (account_id, profile)
+            })
+            .collect()
+    }
+
+    /// Claims a globally unique username, releasing the caller's previous one.
+    ///
+    /// Usernames are 3 to 32 characters of lowercase letters, digits and underscores.
+    pub fn claim_username(&mut self, username: String) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to claim a username."
+        );
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            (MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&username.len())
+                && username
+                    .bytes()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_'),
+            "Usernames must be 3 to 32 lowercase letters, digits or underscores."
+        );
+
+        require!(
+            !self.usernames.contains_key(&username),
+            "The username is already taken."
+        );
+
+        self.internal_release_username(&user_id);
+        self.usernames.insert(username.clone(), user_id.clone());
+        self.username_of_account.insert(user_id, username);
+    }
+
+    pub fn resolve_username(&self, username: String) -> Option<&AccountId> {
+        self.usernames.get(&username)
+    }
+
+    pub fn get_username(&self, account_id: AccountId) -> Option<&String> {
+        self.username_of_account.get(&account_id)
+    }
+
+    /// Marks the caller as active now, without any other side effect.
+    pub fn heartbeat(&mut self) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to send a heartbeat."
+        );
+
+        self.last_active_ms
+            .insert(user_id, env::block_timestamp_ms());
+    }
+
+    pub fn get_last_active(&self, account_id: AccountId) -> Option<u64> {
+        self.last_active_ms.get(&account_id).copied()
+    }
+
+    /// Marks the caller as active in its chat with `receiver_id`, without sending a message.
+    pub fn ping_chat(&mut self, receiver_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+
+        require!(
+            self.chat_participants.contains_key(&chat_id),
+            "There is no chat with this account."
+        );
+        self.assert_not_frozen(&user_id);
+
+        let now = env::block_timestamp_ms();
+        let presence = self.chat_presence.entry(chat_id).or_insert((None, None));
+        if user_id <= receiver_id {
+            presence.0 = Some(now);
+        }
+        if receiver_id <= user_id {
+            presence.1 = Some(now);
+        }
+    }
+
+    /// Returns when `user_id` and `receiver_id`, in that order, last pinged their chat.
+    pub fn get_chat_presence(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+    ) -> (Option<u64>, Option<u64>) {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let Some(&(first, second)) = self.chat_presence.get(&chat_id) else {
+            return (None, None);
+        };
+
+        if user_id <= receiver_id {
+            (first, second)
+        } else {
+            (second, first)
+        }
+    }
+
+    /// Deletes the caller's account along with up to `limit` of its friendships and chats.
+    ///
+    /// Returns whether nothing is left to clean; if not, call again to continue.
+    #[payable]
+    pub fn delete_account(&mut self, limit: Option<u32>) -> bool {
+        assert_one_yocto();
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        self.internal_remove_user(&user_id, limit.unwrap_or(DEFAULT_REMOVAL_LIMIT))
+    }
+
+    /// Removes every message of the chat between the caller and `receiver_id`.
+    ///
+    /// The chat itself is kept until it is pruned with `prune_empty_chats`. Client
+    /// nonces and the run of repeated messages point at the removed messages, so they
+    /// are reset too.
+    pub fn clear_chat(&mut self, receiver_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        require!(
+            self.messages.contains_key(&chat_id),
+            "The chat does not exist."
+        );
+
+        self.internal_forget_messages(chat_id, (&user_id, &receiver_id));
+        self.messages.get_mut(&chat_id).unwrap().clear();
+        self.client_nonces.remove(&chat_id);
+        self.message_repeats.remove(&chat_id);
+    }
+
+    /// Merges the baseline messages stored under the legacy chat ids of each pair into
+    /// their canonical chat, after `migrate`, and restores the pair's baseline
+    /// friendship.
+    ///
+    /// Legacy ids were the unsalted hash of the sender and the receiver concatenated
+    /// without a delimiter. They are one-way and different pairs can share one, so the
+    /// accounts are needed to find both directions of a chat, its canonical id and who
+    /// sent the messages under each: this takes account pairs rather than chat ids.
+    ///
+    /// Every baseline chat stored its messages at the same indices of one shared
+    /// prefix, so an index holds the message last stored there by any chat. Messages
+    /// not sent by the sender of a legacy id are skipped, as they belong to another
+    /// chat; the ones that were overwritten are lost. The baseline messages stay in
+    /// storage, as their slots are shared.
+    ///
+    /// The baseline messages, ordered by `created_at_ms` with the first account's on
+    /// ties, get new ids and come before the messages already in the canonical chat,
+    /// whose `reply_to` indices are shifted accordingly. Read receipts, which count
+    /// positions too, are left as they are. A legacy chat is only merged once. Returns
+    /// the number of chats merged; pairs without legacy messages are skipped.
+    pub fn recanonicalize_chats(&mut self, pairs: Vec<(AccountId, AccountId)>) -> u32 {
+        self.assert_owner();
+
+        require!(
+            pairs.len() <= MAX_RECANONICALIZE_BATCH,
+            format!(
+                "Cannot recanonicalize more than {} chats at once.",
+                MAX_RECANONICALIZE_BATCH
+            )
+        );
+
+        let legacy_chats: LookupMap<CryptoHash, Vector<LegacyMessage>> =
+            LookupMap::new(StorageKey::Messages);
+
+        let mut merged = 0;
+        for (user_id, receiver_id) in pairs {
+            if user_id == receiver_id {
+                continue;
+            }
+            let (first, second) = if user_id < receiver_id {
+                (user_id, receiver_id)
+            } else {
+                (receiver_id, user_id)
+            };
+
+            let were_friends = LookupMap::<AccountId, bool>::new(StorageKey::FriendOfUser {
+                user_id: first.clone(),
+            })
+            .get(&second)
+            .is_some_and(|is_friend| *is_friend);
+            if were_friends && self.users.contains(&first) && self.users.contains(&second) {
+                self.internal_add_friendship(first.clone(), second.clone());
+            }
+
+            let mut legacy = Vec::new();
+            for (author, receiver) in [(&first, &second), (&second, &first)] {
+                let legacy_id = Self::legacy_chat_id(author, receiver);
+                let Some(messages) = legacy_chats.get(&legacy_id) else {
+                    continue;
+                };
+                if !self.merged_legacy_chats.insert((legacy_id, author.clone())) {
+                    continue;
+                }
+                legacy.extend(
+                    messages
+                        .iter()
+                        .filter(|message| message.author == *author)
+                        .map(|message| Message {
+                            created_at_ms: message.created_at_ms,
+                            ..Message::new(message.author.clone(), message.content.clone())
+                        }),
+                );
+            }
+            if legacy.is_empty() {
+                continue;
+            }
+            // The sort is stable, so the first account's messages come first on ties.
+            legacy.sort_by_key(|message| message.created_at_ms);
+
+            require!(
+                self.next_legacy_message_id + legacy.len() as u64 <= LEGACY_MESSAGE_IDS,
+                "No message ids are left for baseline messages."
+            );
+
+            let chat_id: CryptoHash = self.internal_chat_id(&first, &second);
+            let participants = self.chat_participants.get(&chat_id);
+            require!(
+                participants.is_none_or(|(a, b)| *a == first && *b == second),
+                "The chat id is already used by another pair of accounts."
+            );
+            let is_new_chat = participants.is_none();
+
+            let canonical = self
+                .messages
+                .entry(chat_id)
+                .or_insert_with(|| Vector::new(StorageKey::Message { chat_id }));
+            let existing: Vec<Message> = canonical.drain(..).collect();
+            let offset = legacy.len() as u32;
+            for mut message in legacy {
+                message.id = self.next_legacy_message_id;
+                self.next_legacy_message_id += 1;
+                canonical.push(message);
+            }
+            for mut message in existing {
+                message.reply_to = message.reply_to.map(|reply_to| reply_to + offset);
+                canonical.push(message);
+            }
+            self.total_messages += offset as u64;
+
+            if is_new_chat {
+                self.chat_participants
+                    .insert(chat_id, (first.clone(), second.clone()));
+                self.chat_ids.insert(chat_id);
+            }
+            self.internal_add_chat_partner(first.clone(), second.clone());
+            self.internal_add_chat_partner(second, first);
+            merged += 1;
+        }
+
+        merged
+    }
+
+    /// Removes the given chats, and everything recorded about them for their
+    /// participants, if they have no messages left.
+    ///
+    /// Unknown and non-empty chats are skipped. Returns the number of chats pruned.
+    /// Chats with end-to-end encryption enabled keep requiring it, as that can not be
+    /// undone.
+    pub fn prune_empty_chats(&mut self, chat_ids: Vec<Base58CryptoHash>) -> u32 {
+        self.assert_owner();
+
+        require!(
+            chat_ids.len() <= MAX_PRUNE_BATCH,
+            format!("Cannot prune more than {} chats at once.", MAX_PRUNE_BATCH)
+        );
+
+        let mut pruned = 0;
+        for chat_id in chat_ids {
+            let chat_id: CryptoHash = chat_id.into();
+            let is_empty = self
+                .messages
+                .get(&chat_id)
+                .is_some_and(|messages| messages.is_empty());
+
+            if is_empty {
+                self.internal_remove_chat(chat_id);
+                pruned += 1;
+            }
+        }
+
+        pruned
+    }
+
+    /// Marks every message of the chat with `partner_id` up to `up_to_index` as read.
+    pub fn mark_read(&mut self, partner_id: AccountId, up_to_index: u32) {
+        let user_id = env::predecessor_account_id();
+        self.internal_mark_read(user_id, partner_id, up_to_index);
+    }
+
+    /// Applies `mark_read` to each `(partner_id, up_to_index)` entry.
+    ///
+    /// The call is atomic: a single invalid entry aborts the whole batch.
+    pub fn batch_mark_read(&mut self, entries: Vec<(AccountId, u32)>) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            entries.len() <= MAX_MARK_READ_BATCH,
+            format!(
+                "Cannot mark more than {} chats as read at once.",
+                MAX_MARK_READ_BATCH
+            )
+        );
+
+        for (partner_id, up_to_index) in entries {
+            self.internal_mark_read(user_id.clone(), partner_id, up_to_index);
+        }
+    }
+
+    /// Saves the caller's unsent draft for its chat with `receiver_id`.
+    ///
+    /// Saving an empty draft removes it.
+    pub fn save_draft(&mut self, receiver_id: AccountId, content: String) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to save a draft."
+        );
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            content.len() <= MAX_DRAFT_LEN,
+            format!("The draft can not be longer than {} bytes.", MAX_DRAFT_LEN)
+        );
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let drafts = self.drafts.entry(user_id.clone()).or_insert_with(|| {
+            LookupMap::new(StorageKey::DraftsOfUser {
+                user_id: user_id.clone(),
+            })
+        });
+
+        if content.is_empty() {
+            drafts.remove(&chat_id);
+        } else {
+            drafts.insert(chat_id, content);
+        }
+    }
+
+    pub fn get_draft(&self, user_id: AccountId, receiver_id: AccountId) -> Option<&String> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+
+        self.drafts
+            .get(&user_id)
+            .and_then(|drafts| drafts.get(&chat_id))
+    }
+
+    pub fn get_read_receipt(&self, user_id: AccountId, partner_id: AccountId) -> Option<u32> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &partner_id);
+
+        self.read_receipts
+            .get(&user_id)
+            .and_then(|receipts| receipts.get(&chat_id))
+            .copied()
+    }
+
+    pub fn get_unread_count(&self, user_id: AccountId, partner_id: AccountId) -> u32 {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &partner_id);
+        self.internal_unread_count(&user_id, &chat_id)
+    }
+
+    /// Returns the number of unread messages across all chats of `user_id`.
+    ///
+    /// Only the first `MAX_CHATS_SCAN` chats of the user are scanned.
+    pub fn get_total_unread(&self, user_id: AccountId) -> u32 {
+        self.chat_partners
+            .get(&user_id)
+            .map(|partners| {
+                partners
+                    .iter()
+                    .take(MAX_CHATS_SCAN)
+                    .map(|partner_id| {
+                        let chat_id: CryptoHash = self.internal_chat_id(&user_id, partner_id);
+                        self.internal_unread_count(&user_id, &chat_id)
+                    })
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Returns the chats of `user_id` with unread messages, along with their unread count.
+    ///
+    /// Only the first `MAX_CHATS_SCAN` chats of the user are scanned.
+    pub fn get_chats_with_unread(&self, user_id: AccountId) -> Vec<(AccountId, u32)> {
+        let partners = match self.chat_partners.get(&user_id) {
+            Some(partners) => partners,
+            None => return Vec::new(),
+        };
+
+        partners
+            .iter()
+            .take(MAX_CHATS_SCAN)
+            .filter_map(|partner_id| {
+                let chat_id: CryptoHash = self.internal_chat_id(&user_id, partner_id);
+                let unread = self.internal_unread_count(&user_id, &chat_id);
+                (unread > 0).then(|| (partner_id.clone(), unread))
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` messages of `user_id`'s chats containing `query`, ignoring
+    /// case, newest first within each chat.
+    ///
+    /// To bound the gas of the view, only the first `MAX_CHATS_SCAN` chats of the user and
+    /// `MAX_SCAN` messages overall are read; `truncated` tells when more may match.
+    /// Deleted, encrypted and hidden messages are skipped.
+    pub fn search_all_messages(
+        &self,
+        user_id: AccountId,
+        query: String,
+        limit: Option<u32>,
+    ) -> ScanResult<SearchMatch<'_>> {
+        require!(!query.is_empty(), "The search query can not be empty.");
+
+        let Some(partners) = self.chat_partners.get(&user_id) else {
+            return ScanResult {
+                items: Vec::new(),
+                truncated: false,
+            };
+        };
+        let query = query.to_lowercase();
+        let hidden = self.hidden_messages.get(&user_id);
+
+        let entries = partners
+            .iter()
+            .take(MAX_CHATS_SCAN)
+            .filter_map(|partner_id| {
+                let chat_id: CryptoHash = self.internal_chat_id(&user_id, partner_id);
+                self.messages
+                    .get(&chat_id)
+                    .map(|messages| (partner_id, messages))
+            })
+            .flat_map(|(partner_id, messages)| {
+                messages
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(move |(index, message)| SearchMatch {
+                        partner_id,
+                        index: index as u32,
+                        message,
+                    })
+            });
+
+        ScanResult::scan(
+            entries,
+            |found| {
+                let message = found.message;
+                message.is_visible()
+                    && !message.deleted
+                    && message.encryption.is_none()
+                    && !hidden.is_some_and(|hidden| hidden.contains(&message.id))
+                    && message.content.to_lowercase().contains(&query)
+            },
+            0,
+            limit.unwrap_or(10) as usize,
+        )
+    }
+
+    pub fn get_chat_id(&self, user_id: AccountId, receiver_id: AccountId) -> Base58CryptoHash {
+        self.internal_chat_id(&user_id, &receiver_id).into()
+    }
+
+    /// Returns the two accounts recorded when the chat was created, in ascending order.
+    pub fn get_chat_participants(
+        &self,
+        chat_id: Base58CryptoHash,
+    ) -> Option<&(AccountId, AccountId)> {
+        self.chat_participants.get(&CryptoHash::from(chat_id))
+    }
+
+    /// Returns the chat id between two users as a base58 string, for deep links.
+    pub fn get_chat_id_string(&self, user_id: AccountId, receiver_id: AccountId) -> String {
+        String::from(&Base58CryptoHash::from(
+            self.internal_chat_id(&user_id, &receiver_id),
+        ))
+    }
+
+    /// Computes the chat ids of `pairs` and returns every id shared by more than one
+    /// distinct pair, with the number of pairs mapping to it.
+    ///
+    /// The participants recorded for an existing chat count as one of the pairs of its
+    /// id. A pair given in both orders is the same chat and is not a collision. This is
+    /// a view for auditors to check the chat id hashing on real account names.
+    pub fn audit_chat_id_collision(
+        &self,
+        pairs: Vec<(AccountId, AccountId)>,
+    ) -> Vec<(Base58CryptoHash, u32)> {
+        require!(
+            pairs.len() <= MAX_AUDIT_BATCH,
+            format!("Cannot audit more than {} pairs at once.", MAX_AUDIT_BATCH)
+        );
+
+        fn sorted<'a>(a: &'a AccountId, b: &'a AccountId) -> (&'a AccountId, &'a AccountId) {
+            if a <= b {
+                (a, b)
+            } else {
+                (b, a)
+            }
+        }
+
+        let mut pairs_by_id: BTreeMap<CryptoHash, BTreeSet<(&AccountId, &AccountId)>> =
+            BTreeMap::new();
+        for (user_id, receiver_id) in &pairs {
+            let chat_id = self.internal_chat_id(user_id, receiver_id);
+            let id_pairs = pairs_by_id.entry(chat_id).or_default();
+            id_pairs.insert(sorted(user_id, receiver_id));
+            if let Some((first, second)) = self.chat_participants.get(&chat_id) {
+                id_pairs.insert(sorted(first, second));
+            }
+        }
+
+        pairs_by_id
+            .into_iter()
+            .filter(|(_, pairs)| pairs.len() > 1)
+            .map(|(chat_id, pairs)| (chat_id.into(), pairs.len() as u32))
+            .collect()
+    }
+
+    /// Returns the messages between any two users, newest first.
+    ///
+    /// This is a public view meant for auditing; clients should use `get_my_messages`.
+    pub fn get_messages(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+        include_deleted: Option<bool>,
+    ) -> Vec<&Message> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        self.internal_get_messages(
+            chat_id,
+            limit,
+            offset,
+            include_deleted.unwrap_or(false),
+            Some(&user_id),
+        )
+    }
+
+    /// Returns the messages `author_id` sent in the chat between `user_id` and
+    /// `receiver_id`, newest first, among its `MAX_SCAN` newest messages.
+    pub fn get_messages_by_author(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        author_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> ScanResult<&Message> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let Some(messages) = self.messages.get(&chat_id) else {
+            return ScanResult {
+                items: Vec::new(),
+                truncated: false,
+            };
+        };
+        let hidden = self.hidden_messages.get(&user_id);
+
+        ScanResult::scan(
+            messages.iter().rev(),
+            |message| {
+                message.author == author_id
+                    && message.is_visible()
+                    && !message.deleted
+                    && !hidden.is_some_and(|hidden| hidden.contains(&message.id))
+            },
+            offset.unwrap_or(0) as usize,
+            limit.unwrap_or(10) as usize,
+        )
+    }
+
+    /// Returns the messages of the chat with the given base58 `chat_id`, newest first.
+    pub fn get_messages_by_chat_id(
+        &self,
+        chat_id: Base58CryptoHash,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&Message> {
+        self.internal_get_messages(chat_id.into(), limit, offset, false, None)
+    }
+
+    /// Returns the messages between two users, oldest first, e.g. to export a chat.
+    ///
+    /// Deleted messages, and the ones `user_id` deleted for themselves, are left out.
+    /// At most `MAX_USERS_PAGE` messages are returned.
+    pub fn get_oldest_messages(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&Message> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let hidden = self.hidden_messages.get(&user_id);
+
+        self.messages
+            .get(&chat_id)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .filter(|message| {
+                        message.is_visible()
+                            && !message.deleted
+                            && !hidden.is_some_and(|hidden| hidden.contains(&message.id))
+                    })
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(limit.unwrap_or(10).min(MAX_USERS_PAGE) as usize)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the visible high priority messages among the first `MAX_SCAN` messages
+    /// of a chat, oldest first.
+    pub fn get_high_priority_messages(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+    ) -> ScanResult<&Message> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let Some(messages) = self.messages.get(&chat_id) else {
+            return ScanResult {
+                items: Vec::new(),
+                truncated: false,
+            };
+        };
+
+        ScanResult::scan(
+            messages.iter(),
+            |message| {
+                message.priority == Priority::High && message.is_visible() && !message.deleted
+            },
+            0,
+            usize::MAX,
+        )
+    }
+
+    /// Returns the first `MAX_EXPORT_MESSAGES` messages between two users, oldest first,
+    /// as a compact JSON array.
+    ///
+    /// Deleted messages, and the ones `user_id` deleted for themselves, are left out.
+    pub fn export_chat(&self, user_id: AccountId, receiver_id: AccountId) -> String {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let hidden = self.hidden_messages.get(&user_id);
+
+        let messages: Vec<&Message> = self
+            .messages
+            .get(&chat_id)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .filter(|message| {
+                        message.is_visible()
+                            && !message.deleted
+                            && !hidden.is_some_and(|hidden| hidden.contains(&message.id))
+                    })
+                    .take(MAX_EXPORT_MESSAGES)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        near_sdk::serde_json::to_string(&messages)
+            .unwrap_or_else(|_| env::panic_str("Failed to serialize the chat."))
+    }
+
+    /// Returns when the newest visible message between two users was created, so that
+    /// clients can detect new messages without fetching them.
+    ///
+    /// Scheduled messages that are not due yet and expired ones are skipped, among the
+    /// last `MAX_SCAN` messages. `None` if none of them is visible.
+    pub fn get_chat_last_activity(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+    ) -> Option<u64> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+
+        self.messages
+            .get(&chat_id)
+            .and_then(|messages| {
+                messages
+                    .iter()
+                    .rev()
+                    .take(MAX_SCAN)
+                    .find(|message| message.is_visible())
+            })
+            .map(|message| message.created_at_ms)
+    }
+
+    /// Estimates the size, in bytes, of all the messages between two users, so that
+    /// clients can decide whether to page through them.
+    ///
+    /// Only the first `MAX_SCAN` messages are measured; the size of longer chats is
+    /// extrapolated from them.
+    pub fn estimate_chat_size(&self, user_id: AccountId, receiver_id: AccountId) -> u64 {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let Some(messages) = self.messages.get(&chat_id) else {
+            return 0;
+        };
+
+        let scanned = messages.len().min(MAX_SCAN as u32);
+        if scanned == 0 {
+            return 0;
+        }
+        let scanned_bytes: u64 = messages
+            .iter()
+            .take(scanned as usize)
+            .map(|message| MESSAGE_STORAGE_OVERHEAD + message.content.len() as u64)
+            .sum();
+
+        scanned_bytes * messages.len() as u64 / scanned as u64
+    }
+
+    /// Resolves a message by its position in a chat, as emitted by `MessageSent` events.
+    pub fn resolve_message(
+        &self,
+        chat_id: Base58CryptoHash,
+        index: u32,
+    ) -> Option<MessageView<'_>> {
+        self.internal_message_view(chat_id.into(), index)
+    }
+
+    /// Returns the messages the message at `index` replies to, nearest first.
+    ///
+    /// At most `max_depth`, capped at `MAX_REPLY_CHAIN_DEPTH`, ancestors are returned.
+    pub fn get_reply_chain(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        index: u32,
+        max_depth: u32,
+    ) -> Vec<MessageView<'_>> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let max_depth = (max_depth as usize).min(MAX_REPLY_CHAIN_DEPTH);
+
+        let mut chain = Vec::new();
+        let mut index = index;
+        let mut reply_to = self
+            .internal_message_view(chat_id, index)
+            .and_then(|view| view.message.reply_to);
+
+        // Replies always point to an earlier message, which also rules out cycles.
+        while let Some(parent) = reply_to.filter(|parent| *parent < index) {
+            if chain.len() >= max_depth {
+                break;
+            }
+
+            let view = match self.internal_message_view(chat_id, parent) {
+                Some(view) => view,
+                None => break,
+            };
+
+            index = parent;
+            reply_to = view.message.reply_to;
+            chain.push(view);
+        }
+
+        chain
+    }
+
+    /// Returns the message at `index` with up to `radius` messages before and after it,
+    /// oldest first, for "jump to message" views.
+    ///
+    /// `radius` is capped at `MAX_CONTEXT_RADIUS`. Scheduled and deleted messages, and
+    /// the ones `user_id` deleted for themselves, are left out.
+    pub fn get_message_context(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        index: u32,
+        radius: u32,
+    ) -> Vec<MessageView<'_>> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let len = match self.messages.get(&chat_id) {
+            Some(messages) if index < messages.len() => messages.len(),
+            _ => return Vec::new(),
+        };
+        let radius = radius.min(MAX_CONTEXT_RADIUS);
+
+        let start = index.saturating_sub(radius);
+        let end = index.saturating_add(radius).min(len - 1);
+        let hidden = self.hidden_messages.get(&user_id);
+
+        (start..=end)
+            .filter_map(|index| self.internal_message_view(chat_id, index))
+            .filter(|view| {
+                view.message.is_visible()
+                    && !view.message.deleted
+                    && !hidden.is_some_and(|hidden| hidden.contains(&view.message.id))
+            })
+            .collect()
+    }
+
+    /// Same as `get_messages`, along with the number of messages stored in the chat.
+    ///
+    /// `total` also counts the deleted and scheduled messages `items` leaves out, so an
+    /// `offset` at or beyond it is past the end, while a `total` of zero is an empty chat.
+    pub fn get_messages_page(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+        include_deleted: Option<bool>,
+    ) -> Page<&Message> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let total = match self.messages.get(&chat_id) {
+            Some(messages) => messages.len(),
+            None => {
+                return Page {
+                    items: Vec::new(),
+                    total: 0,
+                }
+            }
+        };
+
+        let items = if offset.unwrap_or(0) >= total {
+            Vec::new()
+        } else {
+            self.internal_get_messages(
+                chat_id,
+                limit,
+                offset,
+                include_deleted.unwrap_or(false),
+                Some(&user_id),
+            )
+        };
+
+        Page { items, total }
+    }
+
+    /// Same as `get_messages`, without deleted messages, but returns borsh-serialized
+    /// views, which are cheaper to produce and to parse for indexers.
+    #[result_serializer(borsh)]
+    pub fn get_messages_borsh(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<MessageView<'_>> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let (Some(messages), Some(participants)) = (
+            self.messages.get(&chat_id),
+            self.chat_participants.get(&chat_id),
+        ) else {
+            return Vec::new();
+        };
+        let hidden = self.hidden_messages.get(&user_id);
+
+        messages
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, message)| {
+                message.is_visible()
+                    && !message.deleted
+                    && !hidden.is_some_and(|hidden| hidden.contains(&message.id))
+            })
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(10) as usize)
+            .map(|(index, message)| MessageView {
+                chat_id: chat_id.into(),
+                index: index as u32,
+                participants,
+                message,
+            })
+            .collect()
+    }
+
+    /// Same as `get_message_context`, but borsh-serialized for indexers.
+    #[result_serializer(borsh)]
+    pub fn get_message_context_borsh(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        index: u32,
+        radius: u32,
+    ) -> Vec<MessageView<'_>> {
+        self.get_message_context(user_id, receiver_id, index, radius)
+    }
+
+    /// Returns the messages with an id greater than `after_id`, oldest first.
+    ///
+    /// Unlike offsets, the cursor stays valid when messages are removed from the chat.
+    /// Deleted messages, and the ones `user_id` deleted for themselves, are left out.
+    /// At most `MAX_USERS_PAGE` messages are returned.
+    pub fn get_messages_after_id(
+        &self,
+        user_id: AccountId,
+        receiver_id: AccountId,
+        after_id: Option<u64>,
+        limit: Option<u32>,
+    ) -> MessagesPage<'_> {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        let messages = match self.messages.get(&chat_id) {
+            Some(messages) => messages,
+            None => {
+                return MessagesPage {
+                    messages: Vec::new(),
+                    next_cursor: None,
+                }
+            }
+        };
+
+        // Ids increase with the index, so the first message after the cursor
+        // can be found with a binary search.
+        let (mut start, mut end) = (0, messages.len());
+        if let Some(after_id) = after_id {
+            while start < end {
+                let middle = start + (end - start) / 2;
+                match messages.get(middle) {
+                    Some(message) if message.id <= after_id => start = middle + 1,
+                    _ => end = middle,
+                }
+            }
+        }
+
+        let hidden = self.hidden_messages.get(&user_id);
+        let (indices, page): (Vec<usize>, Vec<&Message>) = messages
+            .iter()
+            .enumerate()
+            .skip(start as usize)
+            .filter(|(_, message)| {
+                message.is_visible()
+                    && !message.deleted
+                    && !hidden.is_some_and(|hidden| hidden.contains(&message.id))
+            })
+            .take(limit.unwrap_or(10).min(MAX_USERS_PAGE) as usize)
+            .unzip();
+
+        let has_more = indices
+            .last()
+            .is_some_and(|index| index + 1 < messages.len() as usize);
+        let next_cursor = page.last().filter(|_| has_more).map(|message| message.id);
+
+        MessagesPage {
+            messages: page,
+            next_cursor,
+        }
+    }
+
+    /// Returns the orphan chats among `limit` chats from `from_index`, for audits after
+    /// forced removals. At most `MAX_SCAN` chats are checked per call.
+    pub fn find_orphan_chats(
+        &self,
+        limit: Option<u32>,
+        from_index: Option<u32>,
+    ) -> Vec<OrphanChat<'_>> {
+        self.chat_ids
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take((limit.unwrap_or(MAX_SCAN as u32) as usize).min(MAX_SCAN))
+            .filter_map(|chat_id| {
+                let participants = self.chat_participants.get(chat_id)?;
+                let missing: Vec<&AccountId> = [&participants.0, &participants.1]
+                    .into_iter()
+                    .filter(|account_id| !self.users.contains(*account_id))
+                    .collect();
+
+                (!missing.is_empty()).then(|| OrphanChat {
+                    chat_id: (*chat_id).into(),
+                    participants,
+                    missing,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the ids of up to `MAX_CHATS_SCAN` chats `user_id` participates in, for
+    /// auditing a user's data.
+    ///
+    /// This is a change method so that the caller is known: only the owner and the
+    /// user itself can list them.
+    pub fn get_all_chat_ids_for_user(
+        &mut self,
+        user_id: AccountId,
+        offset: Option<u32>,
+    ) -> Vec<Base58CryptoHash> {
+        let caller_id = env::predecessor_account_id();
+
+        require!(
+            caller_id == self.owner_id || caller_id == user_id,
+            "Only the owner or the user can list its chats."
+        );
+
+        self.chat_partners
+            .get(&user_id)
+            .map(|partners| {
+                partners
+                    .iter()
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(MAX_CHATS_SCAN)
+                    .map(|partner_id| self.internal_chat_id(&user_id, partner_id).into())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the messages of the caller's chat with `partner_id`, newest first.
+    ///
+    /// This is a change method so that the caller is known: only participants
+    /// can read a chat through it. At most `MAX_USERS_PAGE` messages are returned.
+    pub fn get_my_messages(
+        &mut self,
+        partner_id: AccountId,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&Message> {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to read messages."
+        );
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &partner_id);
+        let hidden = self.hidden_messages.get(&user_id);
+
+        self.messages
+            .get(&chat_id)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .rev()
+                    .filter(|message| {
+                        message.is_visible()
+                            && !message.deleted
+                            && !hidden.is_some_and(|hidden| hidden.contains(&message.id))
+                    })
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(limit.unwrap_or(10).min(MAX_USERS_PAGE) as usize)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Nominates `delegate_id` as the account allowed to freeze the caller's account.
+    #[payable]
+    pub fn set_recovery_delegate(&mut self, delegate_id: AccountId) {
+        assert_one_yocto();
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to set a recovery delegate."
+        );
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            user_id != delegate_id,
+            "You cannot be your own recovery delegate."
+        );
+
+        self.pending_recoveries.remove(&user_id);
+        self.recovery_delegates.insert(user_id, delegate_id);
+    }
+
+    /// Starts the recovery of `account_id`, which can be completed after `RECOVERY_DELAY_MS`.
+    pub fn initiate_recovery(&mut self, account_id: AccountId) {
+        self.assert_recovery_delegate(&account_id);
+
+        require!(
+            !self.frozen_accounts.contains(&account_id),
+            "The account is already frozen."
+        );
+
+        self.pending_recoveries
+            .insert(account_id, env::block_timestamp_ms());
+    }
+
+    /// Lets the account owner abort a recovery while it is still pending.
+    pub fn cancel_recovery(&mut self) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.pending_recoveries.remove(&user_id).is_some(),
+            "There is no pending recovery for your account."
+        );
+    }
+
+    /// Freezes `account_id` once the recovery delay has elapsed.
+    pub fn complete_recovery(&mut self, account_id: AccountId) {
+        self.assert_recovery_delegate(&account_id);
+
+        let initiated_at_ms = self.pending_recoveries.get(&account_id).copied();
+        require!(
+            initiated_at_ms.is_some(),
+            "There is no pending recovery for this account."
+        );
+
+        require!(
+            env::block_timestamp_ms() >= initiated_at_ms.unwrap_or_default() + RECOVERY_DELAY_MS,
+            "The recovery delay has not elapsed yet."
+        );
+
+        self.pending_recoveries.remove(&account_id);
+        self.frozen_accounts.insert(account_id);
+    }
+
+    /// Lets the recovery delegate resume the writes of a frozen account.
+    pub fn unfreeze_account(&mut self, account_id: AccountId) {
+        self.assert_recovery_delegate(&account_id);
+        self.frozen_accounts.remove(&account_id);
+    }
+
+    pub fn get_recovery_delegate(&self, account_id: AccountId) -> Option<&AccountId> {
+        self.recovery_delegates.get(&account_id)
+    }
+
+    pub fn get_pending_recovery(&self, account_id: AccountId) -> Option<u64> {
+        self.pending_recoveries.get(&account_id).copied()
+    }
+
+    pub fn is_frozen(&self, account_id: AccountId) -> bool {
+        self.frozen_accounts.contains(&account_id)
+    }
+
+    /// Returns the profile and join time of `account_id`, or `None` if it is not a user.
+    pub fn get_account_summary(&self, account_id: AccountId) -> Option<AccountSummary> {
+        if !self.users.contains(&account_id) {
+            return None;
+        }
+
+        Some(AccountSummary {
+            profile: self.profiles.get(&account_id).cloned(),
+            joined_at_ms: self.joined_at_ms.get(&account_id).copied().unwrap_or(0),
+            account_id,
+        })
+    }
+
+    /// Returns activity counters of `account_id`, all zeros for unknown accounts.
+    pub fn get_user_stats(&self, account_id: AccountId) -> UserStats {
+        UserStats {
+            messages_sent: self.messages_sent.get(&account_id).copied().unwrap_or(0),
+            friends: self
+                .friends
+                .get(&account_id)
+                .map_or(0, |friends| friends.len()),
+            chats: self
+                .chat_partners
+                .get(&account_id)
+                .map_or(0, |partners| partners.len()),
+            joined_ms: self.joined_at_ms.get(&account_id).copied().unwrap_or(0),
+        }
+    }
+
+    /// Returns up to `limit` users sorted by account id, starting after `cursor`.
+    ///
+    /// Unlike `get_users`, passing the last account of a page as the next `cursor`
+    /// lists every user exactly once, even while accounts register or leave.
+    pub fn get_users_from(&self, cursor: Option<AccountId>, limit: Option<u32>) -> Vec<AccountId> {
+        let limit = limit.unwrap_or(10).min(MAX_USERS_PAGE) as usize;
+
+        match cursor {
+            Some(cursor) => self
+                .sorted_users
+                .iter_from(cursor)
+                .take(limit)
+                .map(|(user_id, _)| user_id)
+                .collect(),
+            None => self
+                .sorted_users
+                .iter()
+                .take(limit)
+                .map(|(user_id, _)| user_id)
+                .collect(),
+        }
+    }
+
+    pub fn get_users(&self, limit: Option<u32>, offset: Option<u32>) -> Vec<&AccountId> {
+        self.users
+            .iter()
+            .rev()
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(10) as usize)
+            .collect()
+    }
+
+    /// Returns users along with their profile, for directories.
+    ///
+    /// At most `MAX_USERS_PAGE` users are returned.
+    pub fn get_users_with_profiles(
+        &self,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<(&AccountId, Option<&Profile>)> {
+        self.users
+            .iter()
+            .rev()
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(10).min(MAX_USERS_PAGE) as usize)
+            .map(|user_id| (user_id, self.profiles.get(user_id)))
+            .collect()
+    }
+
+    pub fn get_contract_stats(&self) -> ContractStats {
+        ContractStats {
+            users: self.users.len(),
+            total_messages: self.total_messages,
+            total_friendships: self.total_friendships,
+            storage_usage: env::storage_usage(),
+        }
+    }
+
+    pub fn get_users_length(&self) -> u32 {
+        self.users.len()
+    }
+
+    /// Gates premium features behind holding `min_balance` of `token_id`.
+    ///
+    /// Passing `None` makes premium features available to every user.
+    pub fn set_premium_token(&mut self, token_id: Option<AccountId>, min_balance: U128) {
+        self.assert_owner();
+
+        self.premium_token_id = token_id;
+        self.premium_min_balance = min_balance.0;
+    }
+
+    /// Sets the fungible token accepted by `ft_on_transfer`, or with `None` stops
+    /// accepting deposits.
+    ///
+    /// Credits are paid out in the configured token, so it can not change while any
+    /// credits are deposited or being withdrawn.
+    pub fn set_credit_token(&mut self, token_id: Option<AccountId>) {
+        self.assert_owner();
+
+        require!(
+            self.total_credits == 0 || token_id == self.credit_token_id,
+            "The credit token can not change while credits are outstanding."
+        );
+
+        self.credit_token_id = token_id;
+    }
+
+    pub fn get_credit_token(&self) -> Option<&AccountId> {
+        self.credit_token_id.as_ref()
+    }
+
+    /// Credits the tokens `sender_id` transferred with `ft_transfer_call` to its balance.
+    ///
+    /// Only `credit_token_id` can call it. The whole amount is returned, for the token
+    /// to refund, if `sender_id` is not a user or `msg` is not empty.
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        require!(
+            self.credit_token_id.as_ref() == Some(&env::predecessor_account_id()),
+            "Only the credit token can be deposited."
+        );
+
+        if !msg.is_empty() || !self.users.contains(&sender_id) {
+            return PromiseOrValue::Value(amount);
+        }
+
+        *self.balances.entry(sender_id).or_insert(0) += amount.0;
+        self.total_credits += amount.0;
+        PromiseOrValue::Value(U128(0))
+    }
+
+    /// Transfers `amount` of the caller's credits back to it as `credit_token_id`.
+    ///
+    /// The credits are restored if the transfer fails.
+    #[payable]
+    pub fn withdraw_credits(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        let user_id = env::predecessor_account_id();
+
+        let token_id = self.credit_token_id.clone();
+        require!(token_id.is_some(), "No credit token is configured.");
+
+        require!(amount.0 > 0, "The amount must be greater than zero.");
+
+        let balance = self.balances.get(&user_id).copied().unwrap_or(0);
+        require!(balance >= amount.0, "Not enough credits.");
+        self.balances.insert(user_id.clone(), balance - amount.0);
+
+        ext_credit_token::ext(token_id.unwrap())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(user_id.clone(), amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_CREDITS_WITHDRAWN)
+                    .on_credits_withdrawn(user_id, amount),
+            )
+    }
+
+    /// Restores the credits of a `withdraw_credits` whose transfer failed.
+    #[private]
+    pub fn on_credits_withdrawn(&mut self, user_id: AccountId, amount: U128) -> bool {
+        if is_promise_success() {
+            self.total_credits -= amount.0;
+            return true;
+        }
+
+        *self.balances.entry(user_id).or_insert(0) += amount.0;
+        false
+    }
+
+    pub fn get_credit_balance(&self, account_id: AccountId) -> U128 {
+        U128(self.balances.get(&account_id).copied().unwrap_or(0))
+    }
+
+    /// Creates a group chat administered by the caller.
+    ///
+    /// This is a premium feature: when a premium token is configured, the group is
+    /// only created once the caller's balance has been checked.
+    pub fn create_group(&mut self, name: String, members: Vec<AccountId>) -> PromiseOrValue<u64> {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to create a group."
+        );
+        self.assert_not_frozen(&user_id);
+        self.assert_valid_group(&user_id, &name, &members);
+
+        let token_id = match &self.premium_token_id {
+            Some(token_id) => token_id.clone(),
+            None => {
+                return PromiseOrValue::Value(self.internal_create_group(user_id, name, members))
+            }
+        };
+
+        ext_premium_token::ext(token_id)
+            .with_static_gas(GAS_FOR_FT_BALANCE_OF)
+            .ft_balance_of(user_id.clone())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_PREMIUM_BALANCE_CHECKED)
+                    .on_premium_balance_checked(user_id, name, members),
+            )
+            .into()
+    }
+
+    /// Completes a `create_group` once the creator's premium balance is known.
+    #[private]
+    pub fn on_premium_balance_checked(
+        &mut self,
+        user_id: AccountId,
+        name: String,
+        members: Vec<AccountId>,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> u64 {
+        let balance = balance.map_or(0, |balance| balance.0);
+
+        require!(
+            balance >= self.premium_min_balance,
+            "Not enough premium tokens to create a group."
+        );
+
+        self.assert_valid_group(&user_id, &name, &members);
+        self.internal_create_group(user_id, name, members)
+    }
+
+    pub fn add_group_member(&mut self, group_id: u64, member_id: AccountId) -> bool {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            self.users.contains(&member_id),
+            "Group members must be users."
+        );
+
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .unwrap_or_else(|| env::panic_str("The group does not exist."));
+
+        require!(
+            group.admin_id == user_id,
+            "Only the group admin can add members."
+        );
+
+        require!(
+            group.members.contains(&member_id) || group.members.len() < self.max_group_members,
+            format!(
+                "A group can not have more than {} members.",
+                self.max_group_members
+            )
+        );
+
+        let is_new_member = group.members.insert(member_id.clone());
+        if is_new_member {
+            self.internal_add_group_membership(member_id, group_id);
+        }
+        is_new_member
+    }
+
+    /// Prevents `member_id` from posting in the group until `until_ms`.
+    ///
+    /// Passing a timestamp in the past unmutes the member.
+    pub fn mute_member(&mut self, group_id: u64, member_id: AccountId, until_ms: u64) {
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .unwrap_or_else(|| env::panic_str("The group does not exist."));
+
+        require!(
+            group.admin_id == user_id,
+            "Only the group admin can mute members."
+        );
+
+        require!(
+            group.members.contains(&member_id),
+            "Only group members can be muted."
+        );
+
+        group.muted_until_ms.insert(member_id, until_ms);
+    }
+
+    pub fn send_group_message(&mut self, group_id: u64, content: String) -> u32 {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to send a message."
+        );
+        self.assert_not_frozen(&user_id);
+
+        let repeat_id = Self::group_repeat_id(group_id);
+        self.assert_valid_content(&repeat_id, &content, false);
+
+        let mut message = Message::new(user_id.clone(), content);
+        message.id = self.next_message_id;
+        self.internal_record_repeat(repeat_id, &message);
+
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .unwrap_or_else(|| env::panic_str("The group does not exist."));
+
+        require!(
+            group.members.contains(&user_id),
+            "You must be a member of the group to send a message."
+        );
+
+        require!(
+            group
+                .muted_until_ms
+                .get(&user_id)
+                .is_none_or(|until_ms| *until_ms <= env::block_timestamp_ms()),
+            "You are muted in this group."
+        );
+
+        group.messages.push(message);
+        let index = group.messages.len() - 1;
+        self.next_message_id += 1;
+        self.total_messages += 1;
+        *self.messages_sent.entry(user_id).or_insert(0) += 1;
+
+        index
+    }
+
+    /// Reacts with `emoji` to a message of a group the caller is a member of.
+    ///
+    /// Returns false if the caller had already reacted with it.
+    pub fn react_to_group_message(&mut self, group_id: u64, index: u32, emoji: String) -> bool {
+        let emoji = Self::normalize_reaction(&emoji);
+        let user_id = env::predecessor_account_id();
+        self.assert_not_frozen(&user_id);
+
+        let message_id = self.internal_group_message_id(group_id, &user_id, index);
+        self.internal_add_reaction(user_id, message_id, emoji)
+    }
+
+    /// Returns how many members reacted with each emoji to a group message.
+    pub fn get_group_message_reactions(&self, group_id: u64, index: u32) -> Vec<(&String, u32)> {
+        let message_id = self
+            .groups
+            .get(&group_id)
+            .and_then(|group| group.messages.get(index))
+            .map(|message| message.id);
+
+        message_id
+            .map(|message_id| self.internal_reaction_counts(message_id))
+            .unwrap_or_default()
+    }
+
+    pub fn get_group(&self, group_id: u64) -> Option<GroupView> {
+        self.groups.get(&group_id).map(|group| GroupView {
+            group_id,
+            name: group.name.clone(),
+            admin_id: group.admin_id.clone(),
+            members_count: group.members.len(),
+            messages_count: group.messages.len(),
+            created_at_ms: group.created_at_ms,
+        })
+    }
+
+    pub fn get_group_members(
+        &self,
+        group_id: u64,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&AccountId> {
+        self.groups
+            .get(&group_id)
+            .map(|group| {
+                group
+                    .members
+                    .iter()
+                    .skip(offset.unwrap_or(0) as usize)
+                    .take(limit.unwrap_or(10) as usize)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the messages of a group, newest first.
+    pub fn get_group_messages(
+        &self,
+        group_id: u64,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Vec<&Message> {
+        self.groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("The group does not exist."))
+            .messages
+            .iter()
+            .rev()
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(10) as usize)
+            .collect()
+    }
+
+    /// Overrides the cost of a byte of storage used in deposit estimates, e.g. to keep
+    /// them stable across protocol upgrades. Passing `None` uses the protocol's cost.
+    pub fn set_storage_cost_override(&mut self, storage_cost: Option<U128>) {
+        self.assert_owner();
+
+        self.storage_cost_override = storage_cost.map(|storage_cost| storage_cost.0);
+    }
+
+    /// Returns the cost of a byte of storage, in yoctoNEAR.
+    pub fn get_storage_byte_cost(&self) -> U128 {
+        U128(self.internal_storage_byte_cost())
+    }
+
+    /// Estimates the deposit, in yoctoNEAR, covering the storage of a message with
+    /// `content_len` bytes of content.
+    pub fn estimate_message_deposit(&self, content_len: u64) -> U128 {
+        let bytes = MESSAGE_STORAGE_OVERHEAD + content_len;
+        U128(bytes as u128 * self.internal_storage_byte_cost())
+    }
+
+    /// Rejects plaintext messages containing `substring`, ignoring case.
+    pub fn add_banned_substring(&mut self, substring: String) {
+        self.assert_owner();
+
+        require!(
+            !substring.is_empty(),
+            "The banned substring can not be empty."
+        );
+
+        require!(
+            self.banned_substrings.len() < MAX_BANNED_SUBSTRINGS,
+            format!("Cannot ban more than {} substrings.", MAX_BANNED_SUBSTRINGS)
+        );
+
+        let substring = substring.to_lowercase();
+        if !self
+            .banned_substrings
+            .iter()
+            .any(|banned| *banned == substring)
+        {
+            self.banned_substrings.push(substring);
+        }
+    }
+
+    pub fn remove_banned_substring(&mut self, substring: String) -> bool {
+        self.assert_owner();
+
+        let substring = substring.to_lowercase();
+        match self
+            .banned_substrings
+            .iter()
+            .position(|banned| *banned == substring)
+        {
+            Some(index) => {
+                self.banned_substrings.swap_remove(index as u32);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_banned_substrings(&self) -> Vec<&String> {
+        self.banned_substrings.iter().collect()
+    }
+
+    /// Sets the message posted to the self-chat of new users; empty disables it.
+    pub fn set_welcome_message(&mut self, welcome_message: String) {
+        self.assert_owner();
+
+        require!(
+            welcome_message.len() <= MAX_WELCOME_MESSAGE_LEN,
+            format!(
+                "The welcome message can not be longer than {} bytes.",
+                MAX_WELCOME_MESSAGE_LEN
+            )
+        );
+
+        self.welcome_message = welcome_message;
+    }
+
+    pub fn get_welcome_message(&self) -> &String {
+        &self.welcome_message
+    }
+
+    /// Sets whether `get_reactors` lists who reacted. Reaction counts stay public either
+    /// way, and the contract state itself remains readable by anyone.
+    pub fn set_reactions_public(&mut self, public: bool) {
+        self.assert_owner();
+
+        self.reactions_public = public;
+    }
+
+    /// Restricts reactions to a whitelist of emojis, which is unrestricted while empty.
+    pub fn add_allowed_reaction(&mut self, emoji: String) -> bool {
+        self.assert_owner();
+
+        self.allowed_reactions
+            .insert(Self::normalize_reaction(&emoji))
+    }
+
+    pub fn remove_allowed_reaction(&mut self, emoji: String) -> bool {
+        self.assert_owner();
+
+        self.allowed_reactions
+            .remove(&Self::normalize_reaction(&emoji))
+    }
+
+    pub fn get_allowed_reactions(&self) -> Vec<&String> {
+        self.allowed_reactions.iter().collect()
+    }
+
+    /// Deployment-level switch to let any user message any other user.
+    pub fn set_require_friendship_to_message(&mut self, required: bool) {
+        self.assert_owner();
+
+        self.require_friendship_to_message = required;
+    }
+
+    pub fn add_moderator(&mut self, account_id: AccountId) -> bool {
+        self.assert_owner();
+
+        self.moderators.insert(account_id)
+    }
+
+    pub fn remove_moderator(&mut self, account_id: AccountId) -> bool {
+        self.assert_owner();
+
+        self.moderators.remove(&account_id)
+    }
+
+    /// Lets `account_id` message anyone, whether or not they are friends.
+    pub fn add_verified(&mut self, account_id: AccountId) -> bool {
+        self.assert_owner();
+
+        self.verified.insert(account_id)
+    }
+
+    pub fn remove_verified(&mut self, account_id: AccountId) -> bool {
+        self.assert_owner();
+
+        self.verified.remove(&account_id)
+    }
+
+    pub fn is_verified(&self, account_id: AccountId) -> bool {
+        self.verified.contains(&account_id)
+    }
+
+    pub fn add_relayer(&mut self, account_id: AccountId) -> bool {
+        self.assert_owner();
+
+        self.relayers.insert(account_id)
+    }
+
+    pub fn remove_relayer(&mut self, account_id: AccountId) -> bool {
+        self.assert_owner();
+
+        self.relayers.remove(&account_id)
+    }
+
+    pub fn get_relayers(&self) -> Vec<&AccountId> {
+        self.relayers.iter().collect()
+    }
+
+    /// Reports `account_id` to the moderators. Returns the id of the report.
+    pub fn file_report(&mut self, account_id: AccountId, reason: String) -> u32 {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to file a report."
+        );
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            !reason.is_empty() && reason.len() <= MAX_REPORT_REASON_LEN,
+            format!(
+                "The reason must be between 1 and {} bytes long.",
+                MAX_REPORT_REASON_LEN
+            )
+        );
+
+        let id = self.reports.len();
+        self.reports.push(Report {
+            id,
+            reporter_id: user_id,
+            reported_id: account_id,
+            reason,
+            created_at_ms: env::block_timestamp_ms(),
+            status: ReportStatus::Open,
+        });
+
+        id
+    }
+
+    pub fn resolve_report(&mut self, report_id: u32, status: ReportStatus) {
+        self.assert_moderator();
+
+        self.reports
+            .get_mut(report_id)
+            .unwrap_or_else(|| env::panic_str("The report does not exist."))
+            .status = status;
+    }
+
+    /// Returns the reports, oldest first, optionally only those with the given status.
+    pub fn get_reports(
+        &self,
+        status: Option<ReportStatus>,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> ScanResult<&Report> {
+        ScanResult::scan(
+            self.reports.iter(),
+            |report| status.is_none_or(|status| report.status == status),
+            offset.unwrap_or(0) as usize,
+            limit.unwrap_or(10) as usize,
+        )
+    }
+
+    /// Deactivates a malicious user and cleans up to `limit` of its friendships and chats.
+    ///
+    /// Returns whether the whole footprint is gone; if not, call it again.
+    pub fn force_remove_user(&mut self, account_id: AccountId, limit: Option<u32>) -> bool {
+        let moderator_id = env::predecessor_account_id();
+
+        self.assert_moderator();
+
+        require!(account_id != self.owner_id, "The owner can not be removed.");
+
+        let is_done =
+            self.internal_remove_user(&account_id, limit.unwrap_or(DEFAULT_REMOVAL_LIMIT));
+
+        Event::UserForceRemoved {
+            account_id: &account_id,
+            moderator_id: &moderator_id,
+        }
+        .emit();
+
+        is_done
+    }
+
+    pub fn get_moderators(&self) -> Vec<&AccountId> {
+        self.moderators.iter().collect()
+    }
+
+    pub fn get_require_friendship_to_message(&self) -> bool {
+        self.require_friendship_to_message
+    }
+
+    /// Sets how many identical consecutive messages a user can send to a chat.
+    ///
+    /// Passing 0 disables the check.
+    pub fn set_max_repeated_messages(&mut self, max_repeated_messages: u32) {
+        self.assert_owner();
+
+        self.max_repeated_messages = max_repeated_messages;
+    }
+
+    pub fn get_max_repeated_messages(&self) -> u32 {
+        self.max_repeated_messages
+    }
+
+    /// Caps the number of registered users, e.g. for a closed beta.
+    ///
+    /// Lowering the cap below the current number of users only blocks new registrations.
+    pub fn set_max_users(&mut self, max_users: u32) {
+        self.assert_owner();
+
+        self.max_users = max_users;
+    }
+
+    pub fn get_max_users(&self) -> u32 {
+        self.max_users
+    }
+
+    /// Caps the number of members in a group, including the admin.
+    ///
+    /// Groups that are already larger keep their members but can not grow.
+    pub fn set_max_group_members(&mut self, max_group_members: u32) {
+        self.assert_owner();
+
+        self.max_group_members = max_group_members;
+    }
+
+    pub fn get_max_group_members(&self) -> u32 {
+        self.max_group_members
+    }
+
+    pub fn set_account_policy(&mut self, account_policy: AccountPolicy) {
+        self.assert_owner();
+
+        self.account_policy = account_policy;
+    }
+
+    pub fn get_account_policy(&self) -> &AccountPolicy {
+        &self.account_policy
+    }
+
+    /// Sets how long deleted or removed accounts have to wait before registering again.
+    pub fn set_reregistration_cooldown(&mut self, cooldown_ms: u64) {
+        self.assert_owner();
+
+        self.reregistration_cooldown_ms = cooldown_ms;
+    }
+
+    pub fn get_reregistration_cooldown(&self) -> u64 {
+        self.reregistration_cooldown_ms
+    }
+
+    /// Sets how long after sending a message its author can edit it.
+    pub fn set_edit_window(&mut self, edit_window_ms: u64) {
+        self.assert_owner();
+
+        self.edit_window_ms = edit_window_ms;
+    }
+
+    pub fn get_edit_window(&self) -> u64 {
+        self.edit_window_ms
+    }
+
+    /// Sets how long messages sent from now on stay visible, or with `None` keeps them
+    /// forever. Messages already sent keep the TTL they were sent with.
+    pub fn set_default_message_ttl(&mut self, ttl_ms: Option<u64>) {
+        self.assert_owner();
+
+        require!(ttl_ms != Some(0), "The message TTL can not be zero.");
+
+        self.default_message_ttl_ms = ttl_ms;
+    }
+
+    pub fn get_default_message_ttl(&self) -> Option<u64> {
+        self.default_message_ttl_ms
+    }
+
+    /// Describes the JSON shape of a `Message`, so clients can adapt to new fields.
+    ///
+    /// Keep in sync with `Message`.
+    pub fn message_schema(&self) -> String {
+        near_sdk::serde_json::json!({
+            "id": "u64",
+            "author": "AccountId",
+            "content": "string",
+            "created_at_ms": "u64",
+            "edited_at_ms": "u64 | null",
+            "quote": { "author": "AccountId", "content": "string", "optional": true },
+            "signature": "string | null",
+            "signer_public_key": "string | null",
+            "reply_to": "u32 | null",
+            "send_at_ms": "u64 | null",
+            "encryption": { "algorithm": "string", "nonce": "string", "optional": true },
+            "deleted": "bool",
+            "kind": "\"Text\" | { \"Poll\": { \"options\": [string] } } | \"System\"",
+            "priority": "\"Normal\" | \"High\"",
+            "expires_at_ms": "u64 | null",
+        })
+        .to_string()
+    }
+
+    /// Always true, as this can only be called on an initialized contract.
+    pub fn is_initialized(&self) -> bool {
+        true
+    }
+
+    pub fn get_owner(&self) -> &AccountId {
+        &self.owner_id
+    }
+
+    pub fn get_membership_minter(&self) -> Option<&AccountId> {
+        self.membership_minter_id
+            .as_ref()
+            .filter(|_| self.membership_badge_enabled)
+    }
+
+    /// Validates and appends `message`, authored by the caller, to its chat with `receiver_id`.
+    fn internal_send_message(
+        &mut self,
+        receiver_id: AccountId,
+        mut message: Message,
+    ) -> SentMessage {
+        let user_id = message.author.clone();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to send a message."
+        );
+        self.assert_not_frozen(&user_id);
+
+        require!(
+            self.users.contains(&receiver_id),
+            "The receiver must be a user to receive a message."
+        );
+
+        require!(
+            !self.internal_is_blocked(&receiver_id, &user_id),
+            "You have been blocked by this user."
+        );
+
+        let is_valid_friend = self.internal_are_friends(&user_id, &receiver_id);
+
+        let accepts_anyone = self
+            .profiles
+            .get(&receiver_id)
+            .is_some_and(|profile| profile.allow_messages_from == MessagePolicy::Anyone);
+
+        require!(
+            !self.require_friendship_to_message
+                || is_valid_friend
+                || accepts_anyone
+                || self.verified.contains(&user_id),
+            "You are not friends with the given receiver."
+        );
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &receiver_id);
+        self.assert_valid_content(&chat_id, &message.content, message.encryption.is_some());
+
+        self.internal_record_repeat(chat_id, &message);
+
+        let participants = self.chat_participants.entry(chat_id).or_insert_with(|| {
+            self.chat_ids.insert(chat_id);
+            if user_id <= receiver_id {
+                (user_id.clone(), receiver_id.clone())
+            } else {
+                (receiver_id.clone(), user_id.clone())
+            }
+        });
+        let (first, second) = &*participants;
+        require!(
+            (*first == user_id && *second == receiver_id)
+                || (*first == receiver_id && *second == user_id),
+            "The chat id is already used by another pair of accounts."
+        );
+
+        self.internal_assign_message_id(&mut message);
+        let messages = self
+            .messages
+            .entry(chat_id)
+            .or_insert_with(|| Vector::new(StorageKey::Message { chat_id }));
+
+        let sent = SentMessage {
+            chat_id: chat_id.into(),
+            index: messages.len(),
+            id: message.id,
+            created_at_ms: message.created_at_ms,
+        };
+        messages.push(message);
+        let index = sent.index;
+
+        Event::MessageSent {
+            chat_id: chat_id.into(),
+            index,
+            author: &user_id,
+        }
+        .emit();
+
+        self.internal_add_chat_partner(user_id.clone(), receiver_id.clone());
+        self.internal_add_chat_partner(receiver_id.clone(), user_id.clone());
+        *self.messages_sent.entry(user_id.clone()).or_insert(0) += 1;
+        self.total_messages += 1;
+        self.last_active_ms
+            .insert(user_id.clone(), env::block_timestamp_ms());
+
+        // Sending a message implies the sender has read the chat up to it.
+        self.internal_mark_read(user_id, receiver_id, index);
+
+        sent
+    }
+
+    /// Gives a new message its id and, if messages expire by default, its expiry.
+    fn internal_assign_message_id(&mut self, message: &mut Message) {
+        message.id = self.next_message_id;
+        self.next_message_id += 1;
+        // Expiry counts from when scheduled messages become visible.
+        message.expires_at_ms = self
+            .default_message_ttl_ms
+            .map(|ttl_ms| message.send_at_ms.unwrap_or(message.created_at_ms) + ttl_ms);
+    }
+
+    /// Tracks identical consecutive messages in a chat, rejecting the send once the
+    /// author exceeds `max_repeated_messages`.
+    fn internal_record_repeat(&mut self, chat_id: CryptoHash, message: &Message) {
+        let content_hash = env::keccak256_array(message.content.as_bytes());
+
+        let count = match self.message_repeats.get(&chat_id) {
+            Some(repeat)
+                if repeat.author == message.author && repeat.content_hash == content_hash =>
+            {
+                repeat.count + 1
+            }
+            _ => 1,
+        };
+
+        require!(
+            self.max_repeated_messages == 0 || count <= self.max_repeated_messages,
+            "You have already sent this message too many times in a row."
+        );
+
+        self.message_repeats.insert(
+            chat_id,
+            MessageRepeat {
+                author: message.author.clone(),
+                content_hash,
+                count,
+            },
+        );
+    }
+
+    fn assert_valid_group(&self, admin_id: &AccountId, name: &str, members: &[AccountId]) {
+        require!(!name.is_empty(), "The group name can not be empty.");
+
+        require!(
+            name.len() <= MAX_GROUP_NAME_LEN,
+            format!(
+                "The group name can not be longer than {} bytes.",
+                MAX_GROUP_NAME_LEN
+            )
+        );
+
+        require!(
+            members
+                .iter()
+                .all(|member_id| self.users.contains(member_id)),
+            "Group members must be users."
+        );
+
+        let mut distinct: Vec<&AccountId> = members
+            .iter()
+            .filter(|member_id| *member_id != admin_id)
+            .collect();
+        distinct.sort();
+        distinct.dedup();
+        require!(
+            distinct.len() < self.max_group_members as usize,
+            format!(
+                "A group can not have more than {} members.",
+                self.max_group_members
+            )
+        );
+    }
+
+    fn internal_create_group(
+        &mut self,
+        admin_id: AccountId,
+        name: String,
+        members: Vec<AccountId>,
+    ) -> u64 {
+        let group_id = self.next_group_id;
+        self.next_group_id += 1;
+
+        let mut group = Group {
+            name,
+            admin_id: admin_id.clone(),
+            members: UnorderedSet::new(StorageKey::GroupMembers { group_id }),
+            messages: Vector::new(StorageKey::GroupMessages { group_id }),
+            created_at_ms: env::block_timestamp_ms(),
+            muted_until_ms: LookupMap::new(StorageKey::GroupMutes { group_id }),
+        };
+        group.members.insert(admin_id);
+        group.members.extend(members);
+        let member_ids: Vec<AccountId> = group.members.iter().cloned().collect();
+
+        self.groups.insert(group_id, group);
+        for member_id in member_ids {
+            self.internal_add_group_membership(member_id, group_id);
+        }
+
+        group_id
+    }
+
+    fn internal_add_group_membership(&mut self, user_id: AccountId, group_id: u64) {
+        self.group_memberships
+            .entry(user_id.clone())
+            .or_insert_with(|| UnorderedSet::new(StorageKey::GroupMembershipsOfUser { user_id }))
+            .insert(group_id);
+    }
+
+    /// The key `message_repeats` tracks a group under, apart from direct chats.
+    fn group_repeat_id(group_id: u64) -> CryptoHash {
+        env::keccak256_array(format!("group:{}", group_id).as_bytes())
+    }
+
+    fn internal_add_friendship(&mut self, user_id: AccountId, friend_id: AccountId) {
+        if !self.friend_edges.insert(self.edge_id(&user_id, &friend_id)) {
+            return;
+        }
+        self.total_friendships += 1;
+
+        let friends = self.friends.entry(user_id.clone()).or_insert_with(|| {
+            UnorderedSet::new(StorageKey::FriendOfUser {
+                user_id: user_id.clone(),
+            })
+        });
+        if friends.insert(friend_id.clone()) {
+            *self.friend_counts.entry(user_id.clone()).or_insert(0) += 1;
+        }
+
+        let friends = self.friends.entry(friend_id.clone()).or_insert_with(|| {
+            UnorderedSet::new(StorageKey::FriendOfUser {
+                user_id: friend_id.clone(),
+            })
+        });
+        if friends.insert(user_id) {
+            *self.friend_counts.entry(friend_id).or_insert(0) += 1;
+        }
+    }
+
+    /// Whether `user_id` blocked `account_id`.
+    fn internal_is_blocked(&self, user_id: &AccountId, account_id: &AccountId) -> bool {
+        self.blocked_users
+            .get(user_id)
+            .is_some_and(|blocked| blocked.contains(account_id))
+    }
+
+    fn internal_remove_friendship(&mut self, user_id: &AccountId, friend_id: &AccountId) {
+        if !self.friend_edges.remove(&self.edge_id(user_id, friend_id)) {
+            return;
+        }
+        self.total_friendships = self.total_friendships.saturating_sub(1);
+
+        for (user_id, friend_id) in [(user_id, friend_id), (friend_id, user_id)] {
+            let removed = self
+                .friends
+                .get_mut(user_id)
+                .is_some_and(|friends| friends.remove(friend_id));
+
+            if removed {
+                if let Some(count) = self.friend_counts.get_mut(user_id) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+            if let Some(friend_labels) = self.friend_labels.get_mut(user_id) {
+                friend_labels.remove(friend_id);
+            }
+        }
+    }
+
+    fn internal_are_friends(&self, user_id: &AccountId, friend_id: &AccountId) -> bool {
+        self.friend_edges
+            .contains(&self.edge_id(user_id, friend_id))
+    }
+
+    /// Identifies the friendship between two accounts, whatever their order.
+    fn edge_id(&self, user_id: &AccountId, friend_id: &AccountId) -> CryptoHash {
+        let (first, second) = if user_id <= friend_id {
+            (user_id, friend_id)
+        } else {
+            (friend_id, user_id)
+        };
+
+        env::keccak256_array(format!("{}:{}", first, second).as_bytes())
+    }
+
+    fn internal_has_request(&self, requester_id: &AccountId, target_id: &AccountId) -> bool {
+        self.outgoing_requests
+            .get(requester_id)
+            .is_some_and(|requests| requests.contains(target_id))
+    }
+
+    /// Removes the pending request from both sides, returning whether it existed.
+    fn internal_remove_request(&mut self, requester_id: &AccountId, target_id: &AccountId) -> bool {
+        let removed = self
+            .outgoing_requests
+            .get_mut(requester_id)
+            .is_some_and(|requests| requests.remove(target_id));
+
+        let removed_incoming = self
+            .incoming_requests
+            .get_mut(target_id)
+            .is_some_and(|requests| requests.remove(requester_id));
+        if removed_incoming {
+            if let Some(count) = self.incoming_request_counts.get_mut(target_id) {
+                *count = count.saturating_sub(1);
+            }
+        }
+
+        removed
+    }
+
+    fn internal_register(&mut self, user_id: AccountId) -> bool {
+        if !self.users.insert(user_id.clone()) {
+            return false;
+        }
+        self.sorted_users.insert(&user_id, &());
+
+        self.joined_at_ms
+            .insert(user_id.clone(), env::block_timestamp_ms());
+        if !self.welcome_message.is_empty() {
+            self.internal_post_system_message(user_id, self.welcome_message.clone());
+        }
+        true
+    }
+
+    /// Posts a `System` message by the contract to the self-chat of `user_id`.
+    fn internal_post_system_message(&mut self, user_id: AccountId, content: String) {
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &user_id);
+        let author = env::current_account_id();
+
+        let mut message = Message {
+            kind: MessageKind::System,
+            ..Message::new(author.clone(), content)
+        };
+        self.internal_assign_message_id(&mut message);
+        self.total_messages += 1;
+
+        let messages = self
+            .messages
+            .entry(chat_id)
+            .or_insert_with(|| Vector::new(StorageKey::Message { chat_id }));
+        messages.push(message);
+        let index = messages.len() - 1;
+
+        if self
+            .chat_participants
+            .insert(chat_id, (user_id.clone(), user_id.clone()))
+            .is_none()
+        {
+            self.chat_ids.insert(chat_id);
+        }
+        self.internal_add_chat_partner(user_id.clone(), user_id);
+
+        Event::MessageSent {
+            chat_id: chat_id.into(),
+            index,
+            author: &author,
+        }
+        .emit();
+    }
+
+    /// Removes `account_id` from the users along with up to `limit` of its friendships
+    /// and chats. Returns whether nothing is left to clean.
+    fn internal_remove_user(&mut self, account_id: &AccountId, limit: u32) -> bool {
+        if self.users.remove(account_id) {
+            self.sorted_users.remove(account_id);
+            self.deleted_at_ms
+                .insert(account_id.clone(), env::block_timestamp_ms());
+        }
+        self.profiles.remove(account_id);
+        self.messages_sent.remove(account_id);
+        self.joined_at_ms.remove(account_id);
+        self.last_active_ms.remove(account_id);
+        self.internal_release_username(account_id);
+
+        let mut budget = limit as usize;
+
+        let friend_ids: Vec<AccountId> = self
+            .friends
+            .get(account_id)
+            .map(|friends| friends.iter().take(budget).cloned().collect())
+            .unwrap_or_default();
+        budget -= friend_ids.len();
+
+        for friend_id in friend_ids {
+            self.internal_remove_friendship(account_id, &friend_id);
+        }
+
+        let partner_ids: Vec<AccountId> = self
+            .chat_partners
+            .get(account_id)
+            .map(|partners| partners.iter().take(budget).cloned().collect())
+            .unwrap_or_default();
+        budget -= partner_ids.len();
+
+        for partner_id in partner_ids {
+            let chat_id: CryptoHash = self.internal_chat_id(account_id, &partner_id);
+            self.internal_remove_chat(chat_id);
+            self.encrypted_chats.remove(&chat_id);
+
+            // Chats without recorded participants still link the two accounts.
+            self.internal_unlink_chat(account_id, &partner_id, &chat_id);
+            self.internal_unlink_chat(&partner_id, account_id, &chat_id);
+        }
+
+        let target_ids: Vec<AccountId> = self
+            .outgoing_requests
+            .get(account_id)
+            .map(|requests| requests.iter().take(budget).cloned().collect())
+            .unwrap_or_default();
+        budget -= target_ids.len();
+
+        for target_id in target_ids {
+            self.internal_remove_request(account_id, &target_id);
+        }
+
+        let requester_ids: Vec<AccountId> = self
+            .incoming_requests
+            .get(account_id)
+            .map(|requests| requests.iter().take(budget).cloned().collect())
+            .unwrap_or_default();
+
+        budget -= requester_ids.len();
+
+        for requester_id in requester_ids {
+            self.internal_remove_request(&requester_id, account_id);
+        }
+
+        let group_ids: Vec<u64> = self
+            .group_memberships
+            .get(account_id)
+            .map(|group_ids| group_ids.iter().take(budget).copied().collect())
+            .unwrap_or_default();
+        budget -= group_ids.len();
+
+        for group_id in group_ids {
+            if let Some(group) = self.groups.get_mut(&group_id) {
+                group.members.remove(account_id);
+            }
+            if let Some(group_ids) = self.group_memberships.get_mut(account_id) {
+                group_ids.remove(&group_id);
+            }
+        }
+
+        if let Some(hidden) = self.hidden_messages.get_mut(account_id) {
+            let message_ids: Vec<u64> = hidden.iter().take(budget).copied().collect();
+            for message_id in message_ids {
+                hidden.remove(&message_id);
+            }
+        }
+
+        let has_hidden = self
+            .hidden_messages
+            .get(account_id)
+            .is_some_and(|hidden| !hidden.is_empty());
+
+        let has_groups = self
+            .group_memberships
+            .get(account_id)
+            .is_some_and(|group_ids| !group_ids.is_empty());
+
+        let has_requests = self
+            .outgoing_requests
+            .get(account_id)
+            .is_some_and(|requests| !requests.is_empty())
+            || self
+                .incoming_requests
+                .get(account_id)
+                .is_some_and(|requests| !requests.is_empty());
+
+        let has_friends = self
+            .friends
+            .get(account_id)
+            .is_some_and(|friends| !friends.is_empty());
+        let has_chats = self
+            .chat_partners
+            .get(account_id)
+            .is_some_and(|partners| !partners.is_empty());
+
+        if has_friends || has_chats || has_requests || has_groups || has_hidden {
+            return false;
+        }
+
+        self.group_memberships.remove(account_id);
+        self.outgoing_requests.remove(account_id);
+        self.incoming_requests.remove(account_id);
+        self.incoming_request_counts.remove(account_id);
+        if let Some(mut blocked) = self.blocked_users.remove(account_id) {
+            blocked.clear();
+        }
+        // The entries of these were removed along with each friendship and chat. Other
+        // users' blocks of the account stay, so that it can not get around them by
+        // registering again.
+        self.friend_labels.remove(account_id);
+        self.read_receipts.remove(account_id);
+        self.drafts.remove(account_id);
+        self.hidden_messages.remove(account_id);
+        self.friends.remove(account_id);
+        self.friend_counts.remove(account_id);
+        if let Some(mut accepted) = self.auto_accept.remove(account_id) {
+            accepted.clear();
+        }
+        self.notification_prefs.remove(account_id);
+        self.chat_partners.remove(account_id);
+        // The relay nonce stays, so that messages signed before the removal can not be
+        // relayed again if the account registers with the same key.
+        self.relay_keys.remove(account_id);
+        true
+    }
+
+    /// Removes the messages of a chat and every per-chat entry, including the ones its
+    /// participants keep about it.
+    fn internal_remove_chat(&mut self, chat_id: CryptoHash) {
+        if let Some((first, second)) = self.chat_participants.get(&chat_id).cloned() {
+            self.internal_forget_messages(chat_id, (&first, &second));
+        }
+        if let Some(mut messages) = self.messages.remove(&chat_id) {
+            messages.clear();
+        }
+        if let Some((first, second)) = self.chat_participants.remove(&chat_id) {
+            self.internal_unlink_chat(&first, &second, &chat_id);
+            self.internal_unlink_chat(&second, &first, &chat_id);
+        }
+        self.chat_ids.remove(&chat_id);
+        self.chat_subjects.remove(&chat_id);
+        self.chat_presence.remove(&chat_id);
+        self.client_nonces.remove(&chat_id);
+        self.message_repeats.remove(&chat_id);
+    }
+
+    /// Removes what is kept by message id for every message of a chat, before the
+    /// messages themselves are removed.
+    ///
+    /// Only the participants can vote in the polls of a chat or hide its messages, so
+    /// theirs are the only votes and hidden ids to remove.
+    fn internal_forget_messages(
+        &mut self,
+        chat_id: CryptoHash,
+        participants: (&AccountId, &AccountId),
+    ) {
+        let (first, second) = participants;
+        let message_ids: Vec<u64> = self
+            .messages
+            .get(&chat_id)
+            .map(|messages| messages.iter().map(|message| message.id).collect())
+            .unwrap_or_default();
+
+        for message_id in message_ids {
+            if let Some(mut reactions) = self.reactions.remove(&message_id) {
+                for (_, reactors) in reactions.iter_mut() {
+                    reactors.clear();
+                }
+                reactions.clear();
+            }
+            if let Some(votes) = self.poll_votes.get_mut(&message_id) {
+                votes.remove(first);
+                votes.remove(second);
+            }
+            self.poll_votes.remove(&message_id);
+            self.poll_tallies.remove(&message_id);
+            self.message_tips.remove(&message_id);
+            self.deleted_messages.remove(&message_id);
+            for participant_id in [first, second] {
+                if let Some(hidden) = self.hidden_messages.get_mut(participant_id) {
+                    hidden.remove(&message_id);
+                }
+            }
+        }
+    }
+
+    /// Forgets what `user_id` keeps about its chat with `partner_id`.
+    fn internal_unlink_chat(
+        &mut self,
+        user_id: &AccountId,
+        partner_id: &AccountId,
+        chat_id: &CryptoHash,
+    ) {
+        if let Some(partners) = self.chat_partners.get_mut(user_id) {
+            partners.remove(partner_id);
+        }
+        if let Some(receipts) = self.read_receipts.get_mut(user_id) {
+            receipts.remove(chat_id);
+        }
+        if let Some(drafts) = self.drafts.get_mut(user_id) {
+            drafts.remove(chat_id);
+        }
+    }
+
+    /// Checks content about to be stored in a chat, whether it is sent or edited.
+    fn assert_valid_content(&self, chat_id: &CryptoHash, content: &str, is_encrypted: bool) {
+        require!(!content.is_empty(), "The message can not be empty.");
+
+        require!(
+            is_encrypted || !self.encrypted_chats.contains(chat_id),
+            "This chat only accepts encrypted messages."
+        );
+
+        if !is_encrypted && !self.banned_substrings.is_empty() {
+            let content = content.to_lowercase();
+            require!(
+                !self
+                    .banned_substrings
+                    .iter()
+                    .any(|banned| content.contains(banned.as_str())),
+                "The message contains banned content."
+            );
+        }
+    }
+
+    fn assert_owner(&self) {
+        require!(
+            env::predecessor_account_id() == self.owner_id,
+            "Only the owner can call this method."
+        );
+    }
+
+    /// The owner is implicitly a moderator.
+    fn assert_moderator(&self) {
+        let account_id = env::predecessor_account_id();
+
+        require!(
+            account_id == self.owner_id || self.moderators.contains(&account_id),
+            "Only the owner or a moderator can call this method."
+        );
+    }
+
+    /// Chat settings can be changed by either participant, and only by them.
+    fn assert_chat_participant(&self, chat_id: &CryptoHash) {
+        let user_id = env::predecessor_account_id();
+        let is_participant = self
+            .chat_participants
+            .get(chat_id)
+            .is_some_and(|(first, second)| *first == user_id || *second == user_id);
+
+        require!(
+            is_participant,
+            "Only the participants of the chat can change its settings."
+        );
+        self.assert_not_frozen(&user_id);
+    }
+
+    /// Rejects accounts the `account_policy` does not allow to register.
+    fn validate_account(&self, account_id: &AccountId) {
+        let is_implicit = account_id.as_str().len() == 64
+            && account_id
+                .as_str()
+                .bytes()
+                .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c));
+        require!(
+            self.account_policy.allow_implicit || !is_implicit,
+            "Implicit accounts can not register."
+        );
+
+        if let Some(parent_id) = &self.account_policy.required_parent {
+            require!(
+                account_id
+                    .as_str()
+                    .strip_suffix(parent_id.as_str())
+                    .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+                format!("Only sub-accounts of {} can register.", parent_id)
+            );
+        }
+    }
+
+    fn assert_not_frozen(&self, user_id: &AccountId) {
+        require!(
+            !self.frozen_accounts.contains(user_id),
+            "This account is frozen."
+        );
+    }
+
+    fn assert_recovery_delegate(&self, account_id: &AccountId) {
+        require!(
+            self.recovery_delegates.get(account_id) == Some(&env::predecessor_account_id()),
+            "Only the recovery delegate can recover this account."
+        );
+    }
+
+    fn internal_mark_read(&mut self, user_id: AccountId, partner_id: AccountId, up_to_index: u32) {
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to mark messages as read."
+        );
+        self.assert_not_frozen(&user_id);
+
+        let chat_id: CryptoHash = self.internal_chat_id(&user_id, &partner_id);
+        let messages_len = self
+            .messages
+            .get(&chat_id)
+            .map_or(0, |messages| messages.len());
+
+        require!(
+            up_to_index < messages_len,
+            "The message to mark as read does not exist."
+        );
+
+        let receipts = self
+            .read_receipts
+            .entry(user_id.clone())
+            .or_insert_with(|| {
+                LookupMap::new(StorageKey::ReadReceiptsOfUser {
+                    user_id: user_id.clone(),
+                })
+            });
+
+        let last_read = receipts.entry(chat_id).or_insert(up_to_index);
+        *last_read = (*last_read).max(up_to_index);
+    }
+
+    /// Chat ids are symmetric: both participants share the same chat.
+    fn internal_chat_id(&self, user_id: &AccountId, receiver_id: &AccountId) -> CryptoHash {
+        if user_id <= receiver_id {
+            self.calculate_hash(user_id.as_str(), receiver_id.as_str())
+        } else {
+            self.calculate_hash(receiver_id.as_str(), user_id.as_str())
+        }
+    }
+
+    fn internal_update_profile<F: FnOnce(&mut Profile)>(&mut self, update: F) {
+        let user_id = env::predecessor_account_id();
+
+        require!(
+            self.users.contains(&user_id),
+            "You must be a user to set a profile."
+        );
+        self.assert_not_frozen(&user_id);
+
+        let mut profile = self.profiles.get(&user_id).cloned().unwrap_or_default();
+        update(&mut profile);
+
+        require!(
+            profile.display_name.len() <= MAX_DISPLAY_NAME_LEN,
+            format!(
+                "The display name can not be longer than {} bytes.",
+                MAX_DISPLAY_NAME_LEN
+            )
+        );
+
+        require!(
+            profile.bio.as_ref().map_or(0, String::len) <= MAX_BIO_LEN,
+            format!("The bio can not be longer than {} bytes.", MAX_BIO_LEN)
+        );
+
+        self.profiles.insert(user_id, profile);
+    }
+
+    fn internal_unread_count(&self, user_id: &AccountId, chat_id: &CryptoHash) -> u32 {
+        let messages_len = self
+            .messages
+            .get(chat_id)
+            .map_or(0, |messages| messages.len());
+        let read = self
+            .read_receipts
+            .get(user_id)
+            .and_then(|receipts| receipts.get(chat_id))
+            .map_or(0, |last_read| last_read + 1);
+
+        messages_len.saturating_sub(read)
+    }
+
+    /// Whether `signature` is the base64 ed25519 signature of `payload` by the relay key
+    /// of `author`.
+    fn internal_verify_relay_signature(
+        &self,
+        author: &AccountId,
+        payload: &[u8],
+        signature: &str,
+    ) -> bool {
+        use ed25519_dalek::Verifier;
+
+        let Some(public_key) = self.relay_keys.get(author) else {
+            return false;
+        };
+        // The first byte of a `PublicKey` is its curve type.
+        let Ok(public_key) = ed25519_dalek::PublicKey::from_bytes(&public_key.as_bytes()[1..])
+        else {
+            return false;
+        };
+        let signature = near_sdk::base64::decode(signature)
+            .ok()
+            .and_then(|bytes| ed25519_dalek::Signature::from_bytes(&bytes).ok());
+
+        signature.is_some_and(|signature| public_key.verify(payload, &signature).is_ok())
+    }
+
+    /// Strips variation selectors, so that the text and emoji presentations of the same
+    /// character, such as "❤" and "❤️", count as a single reaction.
+    fn normalize_reaction(emoji: &str) -> String {
+        emoji
+            .chars()
+            .filter(|c| !('\u{FE00}'..='\u{FE0F}').contains(c))
+            .collect()
+    }
+
+    fn internal_reaction_counts(&self, message_id: u64) -> Vec<(&String, u32)> {
+        self.reactions
+            .get(&message_id)
+            .map(|reactions| {
+                reactions
+                    .iter()
+                    .map(|(emoji, reactors)| (emoji, reactors.len()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the id of a message of the group, which `user_id` must be a member of.
+    fn internal_group_message_id(&self, group_id: u64, user_id: &AccountId, index: u32) -> u64 {
+        let group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("The group does not exist."));
+
+        require!(
+            group.members.contains(user_id),
+            "You must be a member of the group to react to its messages."
+        );
+
+        let message = group.messages.get(index);
+        require!(message.is_some(), "The message does not exist.");
+        message.unwrap().id
+    }
+
+    fn internal_add_reaction(
+        &mut self,
+        user_id: AccountId,
+        message_id: u64,
+        emoji: String,
+    ) -> bool {
+        require!(!emoji.is_empty(), "The reaction can not be empty.");
+
+        require!(
+            emoji.len() <= MAX_REACTION_LEN,
+            format!(
+                "The reaction can not be longer than {} bytes.",
+                MAX_REACTION_LEN
+            )
+        );
+
+        require!(
+            self.allowed_reactions.is_empty() || self.allowed_reactions.contains(&emoji),
+            "This reaction is not allowed."
+        );
+
+        self.reactions
+            .entry(message_id)
+            .or_insert_with(|| UnorderedMap::new(StorageKey::ReactionsOfMessage { message_id }))
+            .entry(emoji.clone())
+            .or_insert_with(|| UnorderedSet::new(StorageKey::Reactors { message_id, emoji }))
+            .insert(user_id)
+    }
+
+    fn internal_storage_byte_cost(&self) -> u128 {
+        self.storage_cost_override
+            .unwrap_or_else(env::storage_byte_cost)
+    }
+
+    fn internal_release_username(&mut self, account_id: &AccountId) {
+        if let Some(username) = self.username_of_account.remove(account_id) {
+            self.usernames.remove(&username);
+        }
+    }
+
+    /// Returns the visible messages of a chat, newest first.
+    fn internal_get_messages(
+        &self,
+        chat_id: CryptoHash,
+        limit: Option<u32>,
+        offset: Option<u32>,
+        include_deleted: bool,
+        viewer_id: Option<&AccountId>,
+    ) -> Vec<&Message> {
+        // Messages the viewer deleted for themselves count as deleted for them only.
+        let hidden = viewer_id.and_then(|viewer_id| self.hidden_messages.get(viewer_id));
+        let is_hidden = |message: &Message| {
+            message.deleted || hidden.is_some_and(|hidden| hidden.contains(&message.id))
+        };
+
+        self.messages
+            .get(&chat_id)
+            .unwrap_or_else(|| env::panic_str("The user does not have any messages."))
+            .iter()
+            .rev()
+            .filter(|message| message.is_visible() && (include_deleted || !is_hidden(message)))
+            .skip(offset.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(10) as usize)
+            .collect::<Vec<&Message>>()
+    }
+
+    fn internal_message_view(&self, chat_id: CryptoHash, index: u32) -> Option<MessageView<'_>> {
+        let message = self.messages.get(&chat_id)?.get(index)?;
+        let participants = self.chat_participants.get(&chat_id)?;
+
+        Some(MessageView {
+            chat_id: chat_id.into(),
+            index,
+            participants,
+            message,
+        })
+    }
+
+    fn internal_message_id(&self, user_id: &AccountId, receiver_id: &AccountId, index: u32) -> u64 {
+        let chat_id: CryptoHash = self.internal_chat_id(user_id, receiver_id);
+
+        self.messages
+            .get(&chat_id)
+            .and_then(|messages| messages.get(index))
+            .map(|message| message.id)
+            .unwrap_or_else(|| env::panic_str("The message does not exist."))
+    }
+
+    fn internal_add_chat_partner(&mut self, user_id: AccountId, partner_id: AccountId) {
+        self.chat_partners
+            .entry(user_id.clone())
+            .or_insert_with(|| UnorderedSet::new(StorageKey::ChatPartnersOfUser { user_id }))
+            .insert(partner_id);
+    }
+
+    /// The chat id `a` used for messages to `b` before chat ids were canonical, salted
+    /// and delimited.
+    fn legacy_chat_id(a: &AccountId, b: &AccountId) -> CryptoHash {
+        let value_hash = env::keccak256(format!("{}{}", a, b).as_bytes());
+        let mut res = CryptoHash::default();
+        res.copy_from_slice(&value_hash);
+
+        res
+    }
+
+    fn calculate_hash(&self, a: &str, b: &str) -> CryptoHash {
+        // Account ids can not contain ':', so the delimiter keeps distinct pairs apart.
+        let concatenated_string = format!("{}:{}:{}", self.salt, a, b);
+
+        let value_hash = env::keccak256(concatenated_string.as_bytes());
+        let mut res = CryptoHash::default();
+        res.copy_from_slice(&value_hash);
+
+        res
+    }
+}
+
+#[allow(dead_code, unused)]
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::{
+        borsh::{self, BorshDeserialize, BorshSerialize},
+        env,
+        json_types::{Base58CryptoHash, U128},
+        store::{LookupMap, UnorderedMap, UnorderedSet, Vector},
+        test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder},
+        testing_env, AccountId, BorshStorageKey, CryptoHash, PromiseOrValue, PromiseResult,
+        PublicKey, RuntimeFeesConfig, VMConfig, ONE_NEAR,
+    };
+
+    use crate::{
+        AccountPolicy, AccountSummary, Contract, DeleteScope, EncryptionMeta, FriendAddResult,
+        LegacyContract, LegacyMessage, Message, MessageKind, MessagePolicy, NotificationPrefs,
+        Priority, Profile, Quote, Report, ReportStatus, SocialState, StorageKey, UserStats,
+        DEFAULT_EDIT_WINDOW_MS, DEFAULT_MAX_GROUP_MEMBERS, DEFAULT_MAX_REPEATED_MESSAGES,
+        DELETE_FOR_EVERYONE_WINDOW_MS, LEGACY_MESSAGE_IDS, MAX_CLIENT_NONCES, MAX_PROFILES_BATCH,
+        MAX_SCAN, MAX_USERS_PAGE, MESSAGE_STORAGE_OVERHEAD, RECOVERY_DELAY_MS, RESTORE_WINDOW_MS,
+    };
+
+    fn contract_account() -> AccountId {
+        "contract".parse::<AccountId>().unwrap()
+    }
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
         builder
             .current_account_id(contract_account())
@@ -206,84 +5259,5261 @@ mod tests {
         builder
     }
 
+    fn register_users(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        users: &[AccountId],
+    ) {
+        for user in users {
+            testing_env!(context.predecessor_account_id(user.clone()).build());
+            contract.create_account();
+        }
+    }
+
+    fn befriend(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        user: &AccountId,
+        friend: &AccountId,
+    ) {
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.add_friend(friend.clone());
+    }
+
+    #[test]
+    fn test_new() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(String::new());
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.get_users_length(), 0);
+    }
+
+    #[test]
+    fn test_is_initialized() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(String::new());
+        assert!(contract.is_initialized());
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract has already been initialized")]
+    fn test_new_twice() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(String::new());
+        env::state_write(&contract);
+
+        Contract::new(String::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract is not initialized")]
+    fn test_default() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let _contract = Contract::default();
+    }
+
+    #[test]
+    fn test_create_account() {
+        let user = accounts(2);
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        let is_valid_user = matches!(contract.create_account(), PromiseOrValue::Value(true));
+        assert!(is_valid_user);
+
+        let is_valid_user = contract.users.contains(&user);
+        assert!(is_valid_user);
+
+        let users = contract.get_users(None, None);
+        let is_valid_user = users.contains(&&user);
+        assert!(is_valid_user);
+    }
+
+    #[test]
+    fn test_account_policy() {
+        let owner = accounts(1);
+        let member: AccountId = "alice.company.near".parse().unwrap();
+
+        let mut context = get_context(owner);
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_account_policy(AccountPolicy {
+            allow_implicit: false,
+            required_parent: None,
+        });
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        assert!(matches!(
+            contract.create_account(),
+            PromiseOrValue::Value(true)
+        ));
+        testing_env!(context.predecessor_account_id(member.clone()).build());
+        assert!(matches!(
+            contract.create_account(),
+            PromiseOrValue::Value(true)
+        ));
+
+        contract.account_policy.required_parent = Some("company.near".parse().unwrap());
+        // Users registered before the policy changed can still call `create_account`.
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        assert!(matches!(
+            contract.create_account(),
+            PromiseOrValue::Value(false)
+        ));
+        assert!(contract.users.contains(&member));
+    }
+
+    #[test]
+    #[should_panic(expected = "Implicit accounts can not register.")]
+    fn test_account_policy_rejects_implicit() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_account_policy(AccountPolicy {
+            allow_implicit: false,
+            required_parent: None,
+        });
+
+        let implicit: AccountId = "0a".repeat(32).parse().unwrap();
+        testing_env!(context.predecessor_account_id(implicit).build());
+        contract.create_account();
+    }
+
+    #[test]
+    #[should_panic(expected = "Only sub-accounts of company.near can register.")]
+    fn test_account_policy_requires_parent() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_account_policy(AccountPolicy {
+            allow_implicit: true,
+            required_parent: Some("company.near".parse().unwrap()),
+        });
+
+        // Ending with the parent is not enough, it has to be a sub-account.
+        let outsider: AccountId = "evilcompany.near".parse().unwrap();
+        testing_env!(context.predecessor_account_id(outsider).build());
+        contract.create_account();
+    }
+
+    #[test]
+    fn test_get_users_from() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        let users: Vec<AccountId> = (0..7)
+            .map(|i| format!("user{}.near", i).parse().unwrap())
+            .collect();
+        register_users(&mut context, &mut contract, &users[..5]);
+
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = contract.get_users_from(cursor, Some(2));
+            if page.is_empty() {
+                break;
+            }
+            cursor = page.last().cloned();
+            listed.extend(page);
+
+            // Registrations and removals between pages do not shift the cursor.
+            if listed.len() == 2 {
+                register_users(&mut context, &mut contract, &users[5..]);
+                testing_env!(context.predecessor_account_id(accounts(1)).build());
+                contract.force_remove_user(users[4].clone(), None);
+            }
+        }
+
+        let expected: Vec<AccountId> = users
+            .iter()
+            .filter(|user_id| **user_id != users[4])
+            .cloned()
+            .collect();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn test_welcome_message() {
+        let owner = accounts(1);
+        let user = accounts(2);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            std::slice::from_ref(&accounts(3)),
+        );
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.set_welcome_message("Welcome!".to_string());
+        contract.set_default_message_ttl(Some(1_000));
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        let messages = contract.get_messages(user.clone(), user.clone(), None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Welcome!");
+        assert_eq!(messages[0].kind, MessageKind::System);
+        assert_eq!(messages[0].author, contract_account());
+        assert_eq!(messages[0].expires_at_ms, Some(1_000));
+        assert_eq!(contract.total_messages, 1);
+        assert_eq!(
+            contract.get_all_chat_ids_for_user(user.clone(), None),
+            vec![contract.get_chat_id(user.clone(), user)]
+        );
+
+        // Accounts registered before the welcome message was set did not get it.
+        let chat_id = contract.internal_chat_id(&accounts(3), &accounts(3));
+        assert!(!contract.messages.contains_key(&chat_id));
+    }
+
+    #[test]
+    fn test_max_users() {
+        let owner = accounts(1);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_max_users(2);
+        register_users(&mut context, &mut contract, &[accounts(2), accounts(3)]);
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.set_max_users(3);
+        register_users(&mut context, &mut contract, &[accounts(4)]);
+
+        assert_eq!(contract.get_users_length(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "The maximum number of users has been reached.")]
+    fn test_create_account_over_max_users() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_max_users(2);
+
+        register_users(
+            &mut context,
+            &mut contract,
+            &[accounts(2), accounts(3), accounts(4)],
+        );
+    }
+
+    #[test]
+    fn test_admin_register_accounts() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[accounts(2)]);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let registered = contract.admin_register_accounts(vec![
+            accounts(2),
+            accounts(3),
+            accounts(4),
+            accounts(3),
+        ]);
+
+        assert_eq!(registered, 2);
+        assert_eq!(contract.get_users_length(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_admin_register_accounts_not_owner() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        testing_env!(get_context(accounts(2)).build());
+        contract.admin_register_accounts(vec![accounts(3)]);
+    }
+
+    #[test]
+    fn test_create_account_mints_membership_badge() {
+        let user = accounts(2);
+        let minter = "minter".parse::<AccountId>().unwrap();
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_membership_minter(Some(minter.clone()), true);
+        assert_eq!(contract.get_membership_minter(), Some(&minter));
+
+        testing_env!(context
+            .predecessor_account_id(user.clone())
+            .attached_deposit(ONE_NEAR / 100)
+            .build());
+        let result = contract.create_account();
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        assert!(!contract.users.contains(&user));
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, minter);
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(contract.on_membership_minted(user.clone(), U128(ONE_NEAR / 100)));
+        assert!(contract.users.contains(&user));
+    }
+
+    #[test]
+    fn test_create_account_membership_mint_failure() {
+        let user = accounts(2);
+        let minter = "minter".parse::<AccountId>().unwrap();
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_membership_minter(Some(minter), true);
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.on_membership_minted(user.clone(), U128(ONE_NEAR / 100)));
+        assert!(!contract.users.contains(&user));
+        assert_eq!(contract.get_users_length(), 0);
+    }
+
+    #[test]
+    fn test_membership_minted_after_max_users_reached() {
+        let user = accounts(2);
+        let minter = "minter".parse::<AccountId>().unwrap();
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_membership_minter(Some(minter), true);
+        contract.set_max_users(1);
+        // Registered while the mint of `user` was in flight.
+        contract.admin_register_accounts(vec![accounts(3)]);
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(!contract.on_membership_minted(user.clone(), U128(ONE_NEAR / 100)));
+        assert!(!contract.users.contains(&user));
+        assert_eq!(contract.get_users_length(), 1);
+        assert_eq!(get_created_receipts().len(), 1);
+    }
+
+    #[test]
+    fn test_membership_minted_for_registered_user() {
+        let user = accounts(2);
+        let minter = "minter".parse::<AccountId>().unwrap();
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_membership_minter(Some(minter), true);
+        // A second `create_account` of the same user completed first.
+        contract.admin_register_accounts(vec![user.clone()]);
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(!contract.on_membership_minted(user.clone(), U128(ONE_NEAR / 100)));
+        assert!(contract.users.contains(&user));
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, user);
+    }
+
+    #[test]
+    fn test_join_timestamp() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.block_timestamp(1_700_000_000_000_000_000).build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+        contract.set_display_name("User".to_string());
+
+        testing_env!(context.block_timestamp(1_800_000_000_000_000_000).build());
+        contract.create_account();
+
+        assert_eq!(
+            contract.get_account_summary(user.clone()),
+            Some(AccountSummary {
+                account_id: user,
+                profile: Some(Profile {
+                    display_name: "User".to_string(),
+                    ..Profile::default()
+                }),
+                joined_at_ms: 1_700_000_000_000,
+            })
+        );
+        assert_eq!(contract.get_account_summary(accounts(3)), None);
+    }
+
+    #[test]
+    fn test_add_friend() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        let is_valid_user = matches!(contract.create_account(), PromiseOrValue::Value(true));
+        assert!(is_valid_user);
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        let is_valid_user = matches!(contract.create_account(), PromiseOrValue::Value(true));
+        assert!(is_valid_user);
+
+        assert_eq!(contract.get_users_length(), 2);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.add_friend(friend.clone());
+        assert!(contract.friends.get(&user).unwrap().contains(&friend));
+    }
+
+    #[test]
+    fn test_add_friend_result() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        assert_eq!(contract.add_friend(friend.clone()), FriendAddResult::Added);
+        assert_eq!(
+            contract.add_friend(friend.clone()),
+            FriendAddResult::AlreadyFriends
+        );
+
+        testing_env!(context.predecessor_account_id(friend).build());
+        assert_eq!(contract.add_friend(user), FriendAddResult::AlreadyFriends);
+    }
+
+    #[test]
+    fn test_send_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.create_account();
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.add_friend(friend.clone());
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+
+        let chat_id: CryptoHash = contract
+            .send_message(friend, "Hello World!".to_string(), None, None)
+            .chat_id
+            .into();
+        println!("{:?}", contract.messages.get(&chat_id).unwrap().get(0));
+        let is_message_added = contract.messages.contains_key(&chat_id);
+        assert!(is_message_added);
+    }
+
+    #[test]
+    fn test_send_message_with_client_nonce() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        let nonce = Some("retry-1".to_string());
+        let sent =
+            contract.send_message(friend.clone(), "Hello".to_string(), Some(0), nonce.clone());
+        // The retry would fail the stale `expected_count` if it were sent again.
+        let resent =
+            contract.send_message(friend.clone(), "Hello".to_string(), Some(0), nonce.clone());
+        assert_eq!(resent, sent);
+        assert_eq!(
+            contract
+                .get_messages_page(user.clone(), friend.clone(), None, None, None)
+                .total,
+            1
+        );
+
+        // Nonces are scoped to their author.
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        let reply = contract.send_message(user.clone(), "Hi".to_string(), None, nonce);
+        assert_eq!(reply.index, 1);
+        assert_eq!(
+            contract
+                .get_messages_page(user, friend, None, None, None)
+                .total,
+            2
+        );
+    }
+
+    #[test]
+    fn test_clear_chat_resets_client_nonces() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        let nonce = Some("retry-1".to_string());
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        let sent = contract.send_message(friend.clone(), "Hi".to_string(), None, nonce.clone());
+        assert_eq!(sent.index, 1);
+        contract.clear_chat(friend.clone());
+        let chat_id = contract.internal_chat_id(&user, &friend);
+        assert!(!contract.client_nonces.contains_key(&chat_id));
+        assert!(!contract.message_repeats.contains_key(&chat_id));
+
+        let resent = contract.send_message(friend.clone(), "Hi".to_string(), None, nonce);
+        assert_eq!(resent.index, 0);
+        assert_ne!(resent.id, sent.id);
+    }
+
+    #[test]
+    fn test_client_nonce_history_is_bounded() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        for i in 0..=MAX_CLIENT_NONCES {
+            contract.send_message(
+                friend.clone(),
+                format!("Message {}", i),
+                None,
+                Some(i.to_string()),
+            );
+        }
+        let chat_id = contract.internal_chat_id(&user, &friend);
+        assert_eq!(
+            contract.client_nonces.get(&chat_id).unwrap().len(),
+            MAX_CLIENT_NONCES
+        );
+
+        // The oldest nonce was forgotten, so it sends a new message.
+        let sent = contract.send_message(
+            friend.clone(),
+            "Again".to_string(),
+            None,
+            Some("0".to_string()),
+        );
+        assert_eq!(sent.index, MAX_CLIENT_NONCES as u32 + 1);
+        let resent = contract.send_message(
+            friend.clone(),
+            "Again".to_string(),
+            None,
+            Some("2".to_string()),
+        );
+        assert_eq!(resent.index, 2);
+    }
+
+    #[test]
+    fn test_banned_substrings() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner);
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.add_banned_substring("Spam".to_string());
+        contract.add_banned_substring("scam".to_string());
+        assert!(contract.remove_banned_substring("SCAM".to_string()));
+        assert_eq!(contract.get_banned_substrings(), vec!["spam"]);
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Not a scam".to_string(), None, None);
+        assert_eq!(
+            contract.get_messages(user, friend, None, None, None).len(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The message contains banned content.")]
+    fn test_send_message_with_banned_substring() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.add_banned_substring("spam".to_string());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend, "Buy SPAM now".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The message contains banned content.")]
+    fn test_edit_message_with_banned_substring() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.add_banned_substring("spam".to_string());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.edit_message(friend, 0, "Buy SPAM now".to_string());
+    }
+
+    #[test]
+    fn test_repeated_message_counter_resets() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_max_repeated_messages(2);
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "Bye".to_string(), None, None);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        // A reply from the other participant also breaks the run.
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.send_message(user.clone(), "Hello".to_string(), None, None);
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        assert_eq!(
+            contract
+                .get_messages(user, friend, Some(10), None, None)
+                .len(),
+            7
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You have already sent this message too many times in a row.")]
+    fn test_repeated_message_over_limit() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        for _ in 0..=DEFAULT_MAX_REPEATED_MESSAGES {
+            contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        }
+    }
+
+    #[test]
+    fn test_send_signed_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        let signature = near_sdk::base64::encode([7; 64]);
+        let public_key = "ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp".to_string();
+        contract.send_signed_message(
+            friend.clone(),
+            "Hello".to_string(),
+            signature.clone(),
+            public_key.clone(),
+        );
+
+        let messages = contract.get_messages(user, friend, None, None, None);
+        assert_eq!(messages[0].signature, Some(signature));
+        assert_eq!(messages[0].signer_public_key, Some(public_key));
+    }
+
+    #[test]
+    #[should_panic(expected = "The signature must be a base64 encoded ed25519 signature.")]
+    fn test_send_signed_message_with_short_signature() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_signed_message(
+            friend,
+            "Hello".to_string(),
+            near_sdk::base64::encode([7; 32]),
+            "ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_send_message_returns_position() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "World".to_string(), None, None);
+
+        testing_env!(context.block_timestamp(7_000_000).build());
+        let length = contract
+            .get_messages(user.clone(), friend.clone(), Some(100), None, Some(true))
+            .len() as u32;
+        let sent = contract.send_message(friend.clone(), "Again".to_string(), None, None);
+
+        assert_eq!(
+            sent.chat_id,
+            contract.get_chat_id(user.clone(), friend.clone())
+        );
+        assert_eq!(sent.index, length);
+        assert_eq!(sent.created_at_ms, 7);
+        let message = contract
+            .resolve_message(sent.chat_id, sent.index)
+            .unwrap()
+            .message;
+        assert_eq!(message.id, sent.id);
+        assert_eq!(message.content, "Again");
+    }
+
+    #[test]
+    fn test_send_message_with_expected_count() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Hello".to_string(), Some(0), None);
+        contract.send_message(friend.clone(), "World".to_string(), Some(1), None);
+
+        assert_eq!(
+            contract.get_messages(user, friend, None, None, None).len(),
+            2
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The chat has changed since it was last read.")]
+    fn test_send_message_with_stale_expected_count() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend, "World".to_string(), Some(0), None);
+    }
+
+    #[test]
+    fn test_get_all_chat_ids_for_user() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other_friend);
+        let chat_id = contract
+            .send_message(friend.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+        let other_chat_id = contract
+            .send_message(other_friend.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+
+        let chat_ids = contract.get_all_chat_ids_for_user(user.clone(), None);
+        assert_eq!(chat_ids.len(), 2);
+        assert!(chat_ids.contains(&chat_id));
+        assert!(chat_ids.contains(&other_chat_id));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        assert_eq!(
+            contract.get_all_chat_ids_for_user(friend, None),
+            vec![chat_id]
+        );
+    }
+
+    #[test]
+    fn test_remove_reaction_only_removes_own() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        assert!(contract.react_to_message(friend.clone(), 0, "👍".to_string()));
+        assert!(!contract.react_to_message(friend.clone(), 0, "👍".to_string()));
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        assert!(!contract.remove_reaction(user.clone(), 0, "👍".to_string()));
+        assert_eq!(
+            contract.get_reactions(user.clone(), friend.clone(), 0),
+            vec![(&"👍".to_string(), 1)]
+        );
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        assert!(contract.remove_reaction(friend.clone(), 0, "👍".to_string()));
+        assert!(contract.get_reactions(user, friend, 0).is_empty());
+    }
+
+    #[test]
+    fn test_reaction_variants_share_a_bucket() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        assert!(contract.react_to_message(friend.clone(), 0, "\u{2764}\u{FE0F}".to_string()));
+        assert!(!contract.react_to_message(friend.clone(), 0, "\u{2764}".to_string()));
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        assert!(contract.react_to_message(user.clone(), 0, "\u{2764}".to_string()));
+        assert_eq!(
+            contract.get_reactions(user.clone(), friend.clone(), 0),
+            vec![(&"\u{2764}".to_string(), 2)]
+        );
+
+        assert!(contract.remove_reaction(user.clone(), 0, "\u{2764}\u{FE0F}".to_string()));
+        assert_eq!(
+            contract.get_reactions(user, friend, 0),
+            vec![(&"\u{2764}".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_get_reactors() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.react_to_message(friend.clone(), 0, "👍".to_string());
+
+        assert_eq!(
+            contract.get_reactors(user.clone(), friend.clone(), 0, "👍".to_string()),
+            vec![&user]
+        );
+        assert!(contract
+            .get_reactors(user.clone(), friend.clone(), 0, "🎉".to_string())
+            .is_empty());
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.set_reactions_public(false);
+        assert_eq!(
+            contract.get_reactions(user, friend, 0),
+            vec![(&"👍".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Reactors are private, only reaction counts are available.")]
+    fn test_get_reactors_when_private() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_reactions_public(false);
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.react_to_message(friend.clone(), 0, "👍".to_string());
+
+        contract.get_reactors(user, friend, 0, "👍".to_string());
+    }
+
+    #[test]
+    fn test_get_reply_chain() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Root".to_string(), None, None);
+        contract.send_message(friend.clone(), "Unrelated".to_string(), None, None);
+        contract.send_reply(friend.clone(), "First".to_string(), 0);
+        contract.send_reply(friend.clone(), "Second".to_string(), 2);
+        contract.send_reply(friend.clone(), "Third".to_string(), 3);
+
+        let chain = contract.get_reply_chain(user.clone(), friend.clone(), 4, 10);
+        let contents: Vec<&str> = chain
+            .iter()
+            .map(|view| view.message.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["Second", "First", "Root"]);
+        assert_eq!(chain[2].index, 0);
+
+        let chain = contract.get_reply_chain(user.clone(), friend.clone(), 4, 2);
+        assert_eq!(chain.len(), 2);
+        assert!(contract.get_reply_chain(user, friend, 9, 10).is_empty());
+    }
+
+    #[test]
+    fn test_get_message_context() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        for i in 0..10 {
+            contract.send_message(friend.clone(), format!("Message {}", i), None, None);
+        }
+
+        let indices: Vec<u32> = contract
+            .get_message_context(user.clone(), friend.clone(), 5, 2)
+            .iter()
+            .map(|view| view.index)
+            .collect();
+        assert_eq!(indices, vec![3, 4, 5, 6, 7]);
+
+        let indices: Vec<u32> = contract
+            .get_message_context(user.clone(), friend.clone(), 0, 3)
+            .iter()
+            .map(|view| view.index)
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2, 3]);
+
+        let window = contract.get_message_context(user.clone(), friend.clone(), 9, 3);
+        assert_eq!(window.len(), 4);
+        assert_eq!(window[3].message.content, "Message 9");
+        assert!(contract.get_message_context(user, friend, 10, 3).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The replied message does not exist.")]
+    fn test_send_reply_to_missing_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Root".to_string(), None, None);
+        contract.send_reply(friend, "Reply".to_string(), 1);
+    }
+
+    #[test]
+    fn test_reaction_events() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let chat_id = contract
+            .send_message(friend.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+        let chat_id = String::from(&chat_id);
+
+        contract.react_to_message(friend.clone(), 0, "👍".to_string());
+        assert_eq!(
+            get_logs().last().unwrap(),
+            &format!(
+                r#"EVENT_JSON:{{"standard":"chat","version":"1.0.0","event":"reaction_added","data":{{"chat_id":"{}","index":0,"emoji":"👍","account_id":"{}"}}}}"#,
+                chat_id, user
+            )
+        );
+
+        contract.remove_reaction(friend, 0, "👍".to_string());
+        assert_eq!(
+            get_logs().last().unwrap(),
+            &format!(
+                r#"EVENT_JSON:{{"standard":"chat","version":"1.0.0","event":"reaction_removed","data":{{"chat_id":"{}","index":0,"emoji":"👍","account_id":"{}"}}}}"#,
+                chat_id, user
+            )
+        );
+    }
+
+    #[test]
+    fn test_allowed_reactions() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.add_allowed_reaction("👍".to_string());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        assert!(contract.react_to_message(friend.clone(), 0, "👍".to_string()));
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        assert!(contract.remove_allowed_reaction("👍".to_string()));
+        testing_env!(context.predecessor_account_id(user).build());
+        assert!(contract.react_to_message(friend, 0, "🎉".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "This reaction is not allowed.")]
+    fn test_disallowed_reaction() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.add_allowed_reaction("👍".to_string());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        contract.react_to_message(friend, 0, "🎉".to_string());
+    }
+
+    #[test]
+    fn test_react_with_tip() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let tip = ONE_NEAR / 10;
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        testing_env!(context
+            .predecessor_account_id(friend.clone())
+            .attached_deposit(tip)
+            .build());
+        contract.react_with_tip(user.clone(), 0, "🎉".to_string(), U128(tip));
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, user);
+        assert_eq!(
+            contract.get_reactions(user.clone(), friend.clone(), 0),
+            vec![(&"🎉".to_string(), 1)]
+        );
+
+        testing_env!(
+            context
+                .predecessor_account_id(contract_account())
+                .attached_deposit(0)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(contract.on_tip_transferred(0, friend.clone(), U128(tip)));
+        assert_eq!(contract.get_message_tips(user, friend, 0), U128(tip));
+    }
+
+    #[test]
+    fn test_react_with_tip_transfer_failure() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.on_tip_transferred(0, friend.clone(), U128(ONE_NEAR)));
+        assert_eq!(get_created_receipts()[0].receiver_id, friend);
+        assert_eq!(contract.get_message_tips(user, friend, 0), U128(0));
+    }
+
+    #[test]
+    fn test_message_schema() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        let schema: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(&contract.message_schema()).unwrap();
+        let message = near_sdk::serde_json::to_value(
+            contract.get_messages(user, friend, None, None, None)[0],
+        )
+        .unwrap();
+
+        let schema_fields: Vec<&String> = schema.as_object().unwrap().keys().collect();
+        let message_fields: Vec<&String> = message.as_object().unwrap().keys().collect();
+        assert_eq!(schema_fields, message_fields);
+    }
+
+    #[test]
+    fn test_get_messages_include_deleted() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "Oops".to_string(), None, None);
+        contract.delete_message(friend.clone(), 1, DeleteScope::ForEveryone);
+
+        let messages = contract.get_messages(user.clone(), friend.clone(), None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Hello");
+
+        let messages = contract.get_messages(user, friend, None, None, Some(true));
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].deleted);
+        assert!(messages[0].content.is_empty());
+        assert!(!messages[1].deleted);
+    }
+
+    #[test]
+    fn test_delete_message_for_me() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "World".to_string(), None, None);
+
+        // Either participant can delete any message for themselves.
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.delete_message(user.clone(), 0, DeleteScope::ForMe);
+
+        let messages = contract.get_messages(friend.clone(), user.clone(), None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "World");
+        assert_eq!(contract.get_my_messages(user.clone(), None, None).len(), 1);
+        assert_eq!(
+            contract
+                .get_messages(user.clone(), friend.clone(), None, None, None)
+                .len(),
+            2
+        );
+        assert_eq!(
+            contract
+                .get_messages(friend, user, None, None, Some(true))
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The message can no longer be deleted for everyone.")]
+    fn test_delete_message_for_everyone_after_window() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        testing_env!(context
+            .block_timestamp(DELETE_FOR_EVERYONE_WINDOW_MS * 1_000_000)
+            .build());
+        contract.delete_message(friend, 0, DeleteScope::ForEveryone);
+    }
+
+    #[test]
+    fn test_restore_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Oops".to_string(), None, None);
+        contract.delete_message(friend.clone(), 0, DeleteScope::ForEveryone);
+        assert!(contract
+            .get_messages(user.clone(), friend.clone(), None, None, None)
+            .is_empty());
+
+        testing_env!(context
+            .block_timestamp((RESTORE_WINDOW_MS - 1) * 1_000_000)
+            .build());
+        contract.restore_message(friend.clone(), 0);
+
+        let messages = contract.get_messages(user, friend.clone(), None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Oops");
+        assert!(!messages[0].deleted);
+        let message_id = messages[0].id;
+
+        contract.delete_message(friend, 0, DeleteScope::ForEveryone);
+        assert!(!contract.purge_deleted_message(message_id));
+        testing_env!(context
+            .block_timestamp((2 * RESTORE_WINDOW_MS - 1) * 1_000_000)
+            .build());
+        assert!(contract.purge_deleted_message(message_id));
+        assert!(!contract.deleted_messages.contains_key(&message_id));
+    }
+
+    #[test]
+    #[should_panic(expected = "The message can no longer be restored.")]
+    fn test_restore_message_after_window() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Oops".to_string(), None, None);
+        contract.delete_message(friend.clone(), 0, DeleteScope::ForEveryone);
+
+        testing_env!(context
+            .block_timestamp(RESTORE_WINDOW_MS * 1_000_000)
+            .build());
+        contract.restore_message(friend, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "You can only delete your own messages.")]
+    fn test_delete_message_of_other_author() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        testing_env!(context.predecessor_account_id(friend).build());
+        contract.delete_message(user, 0, DeleteScope::ForEveryone);
+    }
+
+    #[test]
+    fn test_get_messages_by_author() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.send_message(user.clone(), "Hi".to_string(), None, None);
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_message(friend.clone(), "How are you?".to_string(), None, None);
+
+        let result =
+            contract.get_messages_by_author(user.clone(), friend.clone(), user.clone(), None, None);
+        assert!(!result.truncated);
+        let contents: Vec<&str> = result
+            .items
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["How are you?", "Hello"]);
+
+        let result =
+            contract.get_messages_by_author(friend.clone(), user.clone(), friend, None, None);
+        assert_eq!(result.items.len(), 1);
+        assert_eq!(result.items[0].content, "Hi");
+    }
+
+    #[test]
+    fn test_get_oldest_messages() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        for i in 0..3 {
+            contract.send_message(friend.clone(), format!("Message {}", i), None, None);
+        }
+
+        let contents: Vec<String> = contract
+            .get_oldest_messages(user, friend, Some(2), Some(1))
+            .iter()
+            .map(|message| message.content.clone())
+            .collect();
+        assert_eq!(contents, vec!["Message 1", "Message 2"]);
+    }
+
+    #[test]
+    fn test_message_pages_are_capped() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        for i in 0..=MAX_USERS_PAGE {
+            contract.send_message(friend.clone(), format!("Message {}", i), None, None);
+        }
+
+        let limit = Some(u32::MAX);
+        let max = MAX_USERS_PAGE as usize;
+        assert_eq!(
+            contract
+                .get_oldest_messages(user.clone(), friend.clone(), limit, None)
+                .len(),
+            max
+        );
+        assert_eq!(
+            contract
+                .get_messages_after_id(user.clone(), friend.clone(), None, limit)
+                .messages
+                .len(),
+            max
+        );
+        assert_eq!(contract.get_my_messages(friend, limit, None).len(), max);
+    }
+
+    #[test]
+    fn test_get_high_priority_messages() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message_with_priority(friend.clone(), "Urgent".to_string(), Priority::High);
+        contract.send_message_with_priority(friend.clone(), "Later".to_string(), Priority::Normal);
+
+        let result = contract.get_high_priority_messages(friend, user);
+        assert!(!result.truncated);
+        let messages = result.items;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Urgent");
+        assert_eq!(messages[0].priority, Priority::High);
+    }
+
+    #[test]
+    fn test_export_chat() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        testing_env!(context
+            .predecessor_account_id(friend.clone())
+            .block_timestamp(1_000_000_000)
+            .build());
+        contract.send_message(user.clone(), "Hi!".to_string(), None, None);
+
+        let export: Vec<Message> =
+            near_sdk::serde_json::from_str(&contract.export_chat(user, friend.clone())).unwrap();
+        assert_eq!(export.len(), 2);
+        assert_eq!(export[0].content, "Hello");
+        assert_eq!((export[1].id, export[1].created_at_ms), (1, 1_000));
+        assert_eq!(export[1].author, friend);
+    }
+
+    #[test]
+    fn test_schedule_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.schedule_message(friend.clone(), "Later".to_string(), 1_000);
+        contract.send_message(friend.clone(), "Now".to_string(), None, None);
+
+        let messages = contract.get_messages(user.clone(), friend.clone(), None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Now");
+        let page = contract.get_messages_after_id(user.clone(), friend.clone(), None, None);
+        assert_eq!(page.messages.len(), 1);
+
+        testing_env!(context.block_timestamp(1_000_000_000).build());
+        let messages = contract.get_messages(user, friend, None, None, None);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content, "Later");
+    }
+
+    #[test]
+    fn test_default_message_ttl() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Forever".to_string(), None, None);
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.set_default_message_ttl(Some(1_000));
+        assert_eq!(contract.get_default_message_ttl(), Some(1_000));
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_message(friend.clone(), "Ephemeral".to_string(), None, None);
+        assert_eq!(
+            contract
+                .get_messages(user.clone(), friend.clone(), None, None, None)
+                .len(),
+            2
+        );
+
+        testing_env!(context.block_timestamp(1_000_000_000).build());
+        let messages = contract.get_messages(user, friend, None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Forever");
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_set_default_message_ttl_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_default_message_ttl(Some(1_000));
+    }
+
+    #[test]
+    fn test_set_chat_subject() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let chat_id = contract
+            .send_message(friend.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+
+        testing_env!(context.predecessor_account_id(friend).build());
+        contract.set_chat_subject(chat_id, Some("Trip".to_string()));
+        assert_eq!(
+            contract.get_chat_subject(chat_id),
+            Some(&"Trip".to_string())
+        );
+
+        testing_env!(context.predecessor_account_id(user).build());
+        contract.set_chat_subject(chat_id, None);
+        assert_eq!(contract.get_chat_subject(chat_id), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the participants of the chat can change its settings.")]
+    fn test_set_chat_subject_by_non_participant() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let chat_id = contract
+            .send_message(friend, "Hello".to_string(), None, None)
+            .chat_id;
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.set_chat_subject(chat_id, Some("Trip".to_string()));
+    }
+
+    #[test]
+    fn test_send_encrypted_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        assert!(contract.enable_e2e(friend.clone()));
+        assert!(contract.is_e2e_enabled(friend.clone(), user.clone()));
+
+        let encryption = EncryptionMeta {
+            algorithm: "x25519-xsalsa20-poly1305".to_string(),
+            nonce: "bm9uY2U=".to_string(),
+        };
+        contract.send_encrypted_message(friend.clone(), "Y2lwaGVy".to_string(), encryption.clone());
+
+        let messages = contract.get_messages(user, friend, None, None, None);
+        assert_eq!(messages[0].encryption, Some(encryption));
+    }
+
+    #[test]
+    #[should_panic(expected = "This chat only accepts encrypted messages.")]
+    fn test_send_plaintext_to_encrypted_chat() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.enable_e2e(user.clone());
+        testing_env!(context.predecessor_account_id(user).build());
+        contract.send_message(friend, "Hello".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_poll() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_poll(
+            friend.clone(),
+            "Lunch?".to_string(),
+            vec!["Pizza".to_string(), "Sushi".to_string()],
+        );
+
+        contract.vote_poll(friend.clone(), 0, 0);
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.vote_poll(user.clone(), 0, 0);
+        assert_eq!(
+            contract.get_poll_results(user.clone(), friend.clone(), 0),
+            Some(&vec![2, 0])
+        );
+
+        contract.vote_poll(user.clone(), 0, 1);
+        assert_eq!(
+            contract.get_poll_results(user.clone(), friend.clone(), 0),
+            Some(&vec![1, 1])
+        );
+        assert_eq!(
+            contract.get_messages(user, friend, None, None, None)[0].kind,
+            MessageKind::Poll {
+                options: vec!["Pizza".to_string(), "Sushi".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "A poll must have between 2 and 10 options.")]
+    fn test_poll_with_one_option() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_poll(friend, "Lunch?".to_string(), vec!["Pizza".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The poll option does not exist.")]
+    fn test_vote_missing_poll_option() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_poll(
+            friend.clone(),
+            "Lunch?".to_string(),
+            vec!["Pizza".to_string(), "Sushi".to_string()],
+        );
+
+        contract.vote_poll(friend, 0, 2);
+    }
+
+    #[test]
+    fn test_resolve_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        let chat_id = contract
+            .send_message(user.clone(), "Hi!".to_string(), None, None)
+            .chat_id;
+        assert_eq!(
+            get_logs().last().unwrap(),
+            &format!(
+                r#"EVENT_JSON:{{"standard":"chat","version":"1.0.0","event":"message_sent","data":{{"chat_id":"{}","index":1,"author":"{}"}}}}"#,
+                String::from(&chat_id),
+                friend
+            )
+        );
+
+        let view = contract.resolve_message(chat_id, 1).unwrap();
+        assert_eq!(view.participants, &(user.clone(), friend.clone()));
+        assert_eq!(view.message.author, friend);
+        assert_eq!(view.message.content, "Hi!");
+        assert!(contract.resolve_message(chat_id, 2).is_none());
+    }
+
+    #[test]
+    fn test_send_message_to_non_friend_accepting_anyone() {
+        let user = accounts(2);
+        let stranger = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+        testing_env!(context.predecessor_account_id(stranger.clone()).build());
+        contract.create_account();
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.set_profile(Profile {
+            display_name: "User".to_string(),
+            bio: None,
+            allow_messages_from: MessagePolicy::Anyone,
+        });
+        assert_eq!(
+            contract
+                .get_profile(user.clone())
+                .unwrap()
+                .allow_messages_from,
+            MessagePolicy::Anyone
+        );
+
+        testing_env!(context.predecessor_account_id(stranger).build());
+        let chat_id: CryptoHash = contract
+            .send_message(user, "Hello World!".to_string(), None, None)
+            .chat_id
+            .into();
+        assert_eq!(contract.messages.get(&chat_id).unwrap().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "You are not friends with the given receiver.")]
+    fn test_send_message_to_non_friend_accepting_friends_only() {
+        let user = accounts(2);
+        let stranger = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+        contract.set_profile(Profile {
+            display_name: "User".to_string(),
+            bio: None,
+            allow_messages_from: MessagePolicy::default(),
+        });
+        testing_env!(context.predecessor_account_id(stranger).build());
+        contract.create_account();
+
+        contract.send_message(user, "Hello World!".to_string(), None, None);
+    }
+
+    /// Sets up `author` with a relay key and `relayer` as a relayer, returning the keypair.
+    fn setup_relay(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        author: &AccountId,
+        relayer: &AccountId,
+    ) -> ed25519_dalek::Keypair {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let mut key_bytes = vec![0];
+        key_bytes.extend_from_slice(public.as_bytes());
+
+        testing_env!(context.predecessor_account_id(author.clone()).build());
+        contract.set_relay_key(Some(PublicKey::try_from(key_bytes).unwrap()));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.add_relayer(relayer.clone());
+        testing_env!(context.predecessor_account_id(relayer.clone()).build());
+
+        ed25519_dalek::Keypair { secret, public }
+    }
+
+    fn sign_relayed(
+        keypair: &ed25519_dalek::Keypair,
+        author: &AccountId,
+        receiver_id: &AccountId,
+        nonce: u64,
+        content: &str,
+    ) -> String {
+        use ed25519_dalek::Signer;
+
+        let payload = format!(
+            "{}:{}:{}:{}:{}",
+            contract_account(),
+            author,
+            receiver_id,
+            nonce,
+            content
+        );
+        near_sdk::base64::encode(keypair.sign(payload.as_bytes()).to_bytes())
+    }
+
+    #[test]
+    fn test_send_message_as() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let relayer = accounts(4);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let keypair = setup_relay(&mut context, &mut contract, &user, &relayer);
+
+        let signature = sign_relayed(&keypair, &user, &friend, 1, "Hello");
+        let sent = contract.send_message_as(
+            user.clone(),
+            friend.clone(),
+            "Hello".to_string(),
+            1,
+            signature,
+        );
+        assert_eq!(sent.index, 0);
+        assert_eq!(contract.get_relay_nonce(user.clone()), 1);
+        let messages = contract.get_messages(user, friend, None, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].author, accounts(2));
+        assert_eq!(messages[0].content, "Hello");
+    }
+
+    #[test]
+    #[should_panic(expected = "The signature does not match the author's relay key.")]
+    fn test_send_message_as_invalid_signature() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let relayer = accounts(4);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let keypair = setup_relay(&mut context, &mut contract, &user, &relayer);
+
+        let signature = sign_relayed(&keypair, &user, &friend, 1, "Hello");
+        contract.send_message_as(user, friend, "Send me NEAR".to_string(), 1, signature);
+    }
+
+    #[test]
+    #[should_panic(expected = "The nonce has already been used.")]
+    fn test_send_message_as_replayed() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let relayer = accounts(4);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let keypair = setup_relay(&mut context, &mut contract, &user, &relayer);
+
+        let signature = sign_relayed(&keypair, &user, &friend, 1, "Hello");
+        contract.send_message_as(
+            user.clone(),
+            friend.clone(),
+            "Hello".to_string(),
+            1,
+            signature.clone(),
+        );
+        contract.send_message_as(user, friend, "Hello".to_string(), 1, signature);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only relayers can send messages on behalf of users.")]
+    fn test_send_message_as_not_relayer() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        contract.send_message_as(user, friend, "Hello".to_string(), 1, String::new());
+    }
+
+    #[test]
+    fn test_verified_account_messages_non_friend() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let support = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), support.clone()],
+        );
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        assert!(contract.add_verified(support.clone()));
+        assert!(!contract.add_verified(support.clone()));
+        assert!(contract.is_verified(support.clone()));
+
+        testing_env!(context.predecessor_account_id(support).build());
+        contract.send_message(user.clone(), "How can we help?".to_string(), None, None);
+        assert_eq!(
+            contract
+                .get_messages(user, accounts(3), None, None, None)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You are not friends with the given receiver.")]
+    fn test_unverified_account_messages_non_friend() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let support = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), support.clone()],
+        );
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.add_verified(support.clone());
+        assert!(contract.remove_verified(support.clone()));
+
+        testing_env!(context.predecessor_account_id(support).build());
+        contract.send_message(user, "How can we help?".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_send_message_without_required_friendship() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let stranger = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        assert!(contract.get_require_friendship_to_message());
+        contract.set_require_friendship_to_message(false);
+        assert!(!contract.get_require_friendship_to_message());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), stranger.clone()],
+        );
+
+        testing_env!(context.predecessor_account_id(stranger).build());
+        let chat_id: CryptoHash = contract
+            .send_message(user, "Hello World!".to_string(), None, None)
+            .chat_id
+            .into();
+        assert_eq!(contract.messages.get(&chat_id).unwrap().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_set_require_friendship_to_message_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_require_friendship_to_message(false);
+    }
+
+    #[test]
+    fn test_set_display_name_and_bio() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+
+        contract.set_display_name("User".to_string());
+        assert_eq!(
+            contract.get_profile(user.clone()),
+            Some(&Profile {
+                display_name: "User".to_string(),
+                ..Profile::default()
+            })
+        );
+
+        contract.set_profile(Profile {
+            display_name: "User".to_string(),
+            bio: Some("Hello".to_string()),
+            allow_messages_from: MessagePolicy::Anyone,
+        });
+        contract.set_bio(Some("Hello World!".to_string()));
+        contract.set_display_name("New User".to_string());
+        assert_eq!(
+            contract.get_profile(user),
+            Some(&Profile {
+                display_name: "New User".to_string(),
+                bio: Some("Hello World!".to_string()),
+                allow_messages_from: MessagePolicy::Anyone,
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The bio can not be longer than 280 bytes.")]
+    fn test_set_bio_too_long() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+
+        contract.set_bio(Some("a".repeat(281)));
+    }
+
+    #[test]
+    fn test_get_my_messages() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let stranger = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), stranger.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "World".to_string(), None, None);
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        let messages = contract.get_my_messages(user.clone(), None, None);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "World");
+
+        // A third party only ever reads their own chats.
+        testing_env!(context.predecessor_account_id(stranger).build());
+        assert!(contract
+            .get_my_messages(friend.clone(), None, None)
+            .is_empty());
+        assert!(contract
+            .get_my_messages(user.clone(), None, None)
+            .is_empty());
+
+        // The public view stays available for auditing.
+        assert_eq!(
+            contract.get_messages(user, friend, None, None, None).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_get_friends_paginated_sorted() {
+        let user = accounts(0);
+        let friends = [
+            accounts(5),
+            accounts(2),
+            accounts(4),
+            accounts(1),
+            accounts(3),
+        ];
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+        register_users(&mut context, &mut contract, &friends);
+        for friend in &friends {
+            befriend(&mut context, &mut contract, &user, friend);
+        }
+
+        let first_page = contract.get_friends_paginated_sorted(user.clone(), Some(2), None);
+        assert_eq!(first_page, vec![&accounts(1), &accounts(2)]);
+
+        // Removing a friend from a later page must not shift the earlier ones.
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.remove_friend(accounts(4));
+        assert!(!contract.friends.get(&accounts(4)).unwrap().contains(&user));
+
+        let mut pages = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = contract.get_friends_paginated_sorted(user.clone(), Some(2), Some(offset));
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len() as u32;
+            pages.extend(page.into_iter().cloned());
+        }
+        assert_eq!(
+            pages,
+            vec![accounts(1), accounts(2), accounts(3), accounts(5)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You are not friends with this account.")]
+    fn test_remove_friend_not_friends() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[accounts(2), accounts(3)]);
+
+        contract.remove_friend(accounts(3));
+    }
+
+    #[test]
+    fn test_cancel_friend_request() {
+        let user = accounts(2);
+        let target = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), target.clone()]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_friend_request(target.clone());
+        assert_eq!(
+            contract.get_incoming_requests(target.clone(), None, None),
+            vec![&user]
+        );
+        assert_eq!(
+            contract.get_outgoing_requests(user.clone(), None, None),
+            vec![&target]
+        );
+
+        contract.cancel_friend_request(target.clone());
+        assert!(contract
+            .get_incoming_requests(target.clone(), None, None)
+            .is_empty());
+        assert!(contract.get_outgoing_requests(user, None, None).is_empty());
+    }
+
+    #[test]
+    fn test_get_incoming_request_count() {
+        let user = accounts(2);
+        let other_user = accounts(3);
+        let third_user = accounts(4);
+        let target = accounts(5);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[
+                user.clone(),
+                other_user.clone(),
+                third_user.clone(),
+                target.clone(),
+            ],
+        );
+        for requester in [&user, &other_user, &third_user] {
+            testing_env!(context.predecessor_account_id(requester.clone()).build());
+            contract.send_friend_request(target.clone());
+        }
+        assert_eq!(contract.get_incoming_request_count(target.clone()), 3);
+
+        contract.cancel_friend_request(target.clone());
+        assert_eq!(contract.get_incoming_request_count(target.clone()), 2);
+
+        testing_env!(context.predecessor_account_id(target.clone()).build());
+        contract.accept_friend_request(user);
+        assert_eq!(contract.get_incoming_request_count(target.clone()), 1);
+
+        contract.reject_friend_request(other_user);
+        assert_eq!(contract.get_incoming_request_count(target.clone()), 0);
+        assert!(contract
+            .get_incoming_requests(target, None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_accept_friend_request() {
+        let user = accounts(2);
+        let target = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), target.clone()]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_friend_request(target.clone());
+
+        testing_env!(context.predecessor_account_id(target.clone()).build());
+        contract.accept_friend_request(user.clone());
+        assert!(contract.friends.get(&user).unwrap().contains(&target));
+        assert!(contract.friends.get(&target).unwrap().contains(&user));
+        assert!(contract
+            .get_incoming_requests(target, None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_audit_chat_id_collision() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        // These pairs collide when the account ids are concatenated without a delimiter.
+        let pairs = vec![
+            ("aa".parse().unwrap(), "bbb".parse().unwrap()),
+            ("aab".parse().unwrap(), "bb".parse().unwrap()),
+            (accounts(2), accounts(3)),
+            (accounts(3), accounts(2)),
+        ];
+        assert!(contract.audit_chat_id_collision(pairs).is_empty());
+
+        // A chat whose id is recorded for another pair of accounts.
+        let chat_id = contract.internal_chat_id(&accounts(2), &accounts(3));
+        contract
+            .chat_participants
+            .insert(chat_id, ("bbb".parse().unwrap(), "aa".parse().unwrap()));
+        let pairs = vec![
+            (accounts(3), accounts(2)),
+            (accounts(2), accounts(3)),
+            (accounts(2), accounts(4)),
+        ];
+        assert_eq!(
+            contract.audit_chat_id_collision(pairs),
+            vec![(Base58CryptoHash::from(chat_id), 2)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot audit more than 100 pairs at once.")]
+    fn test_audit_chat_id_collision_too_many() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(String::new());
+        contract.audit_chat_id_collision(vec![(accounts(2), accounts(3)); 101]);
+    }
+
+    #[test]
+    fn test_notification_prefs() {
+        let user = accounts(2);
+        let other = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[other.clone(), user.clone()]);
+
+        assert_eq!(
+            contract.get_notification_prefs(user.clone()),
+            NotificationPrefs::default()
+        );
+        assert!(contract.get_notification_prefs(accounts(4)).new_message);
+
+        let prefs = NotificationPrefs {
+            new_message: true,
+            friend_request: false,
+            reactions: false,
+        };
+        contract.set_notification_prefs(prefs.clone());
+        assert_eq!(contract.get_notification_prefs(user), prefs);
+        assert_eq!(
+            contract.get_notification_prefs(other),
+            NotificationPrefs::default()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You must be a user to set notification preferences.")]
+    fn test_set_notification_prefs_not_user() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_notification_prefs(NotificationPrefs::default());
+    }
+
+    #[test]
+    fn test_auto_accept_friend_request() {
+        let user = accounts(2);
+        let target = accounts(3);
+        let other = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), target.clone(), other.clone()],
+        );
+
+        testing_env!(context.predecessor_account_id(target.clone()).build());
+        assert!(contract.add_auto_accept(user.clone()));
+        assert!(!contract.add_auto_accept(user.clone()));
+        assert_eq!(contract.get_auto_accept(target.clone()), vec![&user]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_friend_request(target.clone());
+        assert!(contract.are_friends(user.clone(), target.clone()));
+        assert!(contract
+            .get_incoming_requests(target.clone(), None, None)
+            .is_empty());
+        assert_eq!(contract.get_incoming_request_count(target.clone()), 0);
+
+        testing_env!(context.predecessor_account_id(other.clone()).build());
+        contract.send_friend_request(target.clone());
+        assert!(!contract.are_friends(other.clone(), target.clone()));
+        assert_eq!(
+            contract.get_incoming_requests(target.clone(), None, None),
+            vec![&other]
+        );
+
+        testing_env!(context.predecessor_account_id(target.clone()).build());
+        assert!(contract.remove_auto_accept(user.clone()));
+        assert!(contract.get_auto_accept(target).is_empty());
+    }
+
+    #[test]
+    fn test_auto_accept_clears_pending_request() {
+        let user = accounts(2);
+        let target = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[target.clone(), user.clone()]);
+        contract.send_friend_request(target.clone());
+        assert_eq!(contract.get_incoming_request_count(target.clone()), 1);
+
+        testing_env!(context.predecessor_account_id(target.clone()).build());
+        contract.add_auto_accept(user.clone());
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_friend_request(target.clone());
+
+        assert!(contract.are_friends(user.clone(), target.clone()));
+        assert!(contract
+            .get_outgoing_requests(user.clone(), None, None)
+            .is_empty());
+        assert!(contract
+            .get_incoming_requests(target.clone(), None, None)
+            .is_empty());
+        assert!(contract
+            .get_outgoing_requests(target.clone(), None, None)
+            .is_empty());
+        assert!(contract.get_incoming_requests(user, None, None).is_empty());
+        assert_eq!(contract.get_incoming_request_count(target), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no pending friend request from this account.")]
+    fn test_accept_canceled_friend_request() {
+        let user = accounts(2);
+        let target = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), target.clone()]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_friend_request(target.clone());
+        contract.cancel_friend_request(target.clone());
+
+        testing_env!(context.predecessor_account_id(target).build());
+        contract.accept_friend_request(user);
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no pending friend request to this account.")]
+    fn test_cancel_missing_friend_request() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[accounts(2), accounts(3)]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.cancel_friend_request(accounts(3));
+    }
+
+    #[test]
+    fn test_get_user_stats() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.block_timestamp(5_000_000).build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        assert_eq!(
+            contract.get_user_stats(user.clone()),
+            UserStats {
+                messages_sent: 0,
+                friends: 0,
+                chats: 0,
+                joined_ms: 5,
+            }
+        );
+
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        assert_eq!(contract.get_user_stats(user.clone()).messages_sent, 1);
+        contract.send_message(friend.clone(), "World".to_string(), None, None);
+
+        assert_eq!(
+            contract.get_user_stats(user),
+            UserStats {
+                messages_sent: 2,
+                friends: 1,
+                chats: 1,
+                joined_ms: 5,
+            }
+        );
+        assert_eq!(contract.get_user_stats(friend).messages_sent, 0);
+        assert_eq!(
+            contract.get_user_stats(accounts(5)),
+            UserStats {
+                messages_sent: 0,
+                friends: 0,
+                chats: 0,
+                joined_ms: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_send_quote_is_independent_of_edits() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello World!".to_string(), None, None);
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.send_quote(
+            user.clone(),
+            "Hi!".to_string(),
+            "Hello World!".to_string(),
+            user.clone(),
+        );
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.edit_message(friend.clone(), 0, "Goodbye".to_string());
+
+        let messages = contract.get_messages(user.clone(), friend, None, None, None);
+        assert_eq!(messages[1].content, "Goodbye");
+        assert!(messages[1].edited_at_ms.is_some());
+        assert_eq!(messages[0].content, "Hi!");
+        assert_eq!(
+            messages[0].quote,
+            Some(Quote {
+                author: user,
+                content: "Hello World!".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The quoted content can not be longer than 500 bytes.")]
+    fn test_send_quote_too_long() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.send_quote(friend, "Hi!".to_string(), "a".repeat(501), user);
+    }
+
+    #[test]
+    fn test_edit_message_within_window() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello World!".to_string(), None, None);
+
+        testing_env!(context
+            .block_timestamp((DEFAULT_EDIT_WINDOW_MS - 1) * 1_000_000)
+            .build());
+        contract.edit_message(friend.clone(), 0, "Goodbye".to_string());
+        assert_eq!(
+            contract.get_messages(user, friend, None, None, None)[0].content,
+            "Goodbye"
+        );
+    }
+
+    #[test]
+    fn test_edit_message_with_max_window() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_edit_window(u64::MAX);
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        testing_env!(context.block_timestamp(1_000_000_000).build());
+        contract.send_message(friend.clone(), "Hello World!".to_string(), None, None);
+        contract.edit_message(friend.clone(), 0, "Goodbye".to_string());
+        assert_eq!(
+            contract.get_messages(user, friend, None, None, None)[0].content,
+            "Goodbye"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The message can no longer be edited.")]
+    fn test_edit_message_after_window() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello World!".to_string(), None, None);
+
+        testing_env!(context
+            .block_timestamp(DEFAULT_EDIT_WINDOW_MS * 1_000_000)
+            .build());
+        contract.edit_message(friend, 0, "Goodbye".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "You can only edit your own messages.")]
+    fn test_edit_message_not_author() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello World!".to_string(), None, None);
+
+        testing_env!(context.predecessor_account_id(friend).build());
+        contract.edit_message(user, 0, "Goodbye".to_string());
+    }
+
+    #[test]
+    fn test_find_orphan_chats() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other_friend);
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        let chat_id = contract
+            .send_message(other_friend.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+        assert!(contract.find_orphan_chats(None, None).is_empty());
+
+        // A removal without budget leaves the chats of the account behind.
+        testing_env!(context.predecessor_account_id(owner).build());
+        assert!(!contract.force_remove_user(other_friend.clone(), Some(0)));
+
+        let orphans = contract.find_orphan_chats(None, None);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].chat_id, chat_id);
+        assert_eq!(orphans[0].missing, vec![&other_friend]);
+        assert!(contract.find_orphan_chats(Some(1), Some(2)).is_empty());
+    }
+
+    #[test]
+    fn test_force_remove_user() {
+        let owner = accounts(1);
+        let moderator = accounts(2);
+        let spammer = accounts(3);
+        let user = accounts(4);
+        let friend = accounts(5);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        assert!(contract.add_moderator(moderator.clone()));
+        register_users(
+            &mut context,
+            &mut contract,
+            &[spammer.clone(), user.clone(), friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &spammer, &user);
+        befriend(&mut context, &mut contract, &spammer, &friend);
+        befriend(&mut context, &mut contract, &user, &friend);
+        testing_env!(context.predecessor_account_id(spammer.clone()).build());
+        let spam_chat_id: CryptoHash = contract
+            .send_message(user.clone(), "Spam".to_string(), None, None)
+            .chat_id
+            .into();
+        contract.send_message(friend.clone(), "Spam".to_string(), None, None);
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+
+        let target: AccountId = "target".parse().unwrap();
+        let requester: AccountId = "requester".parse().unwrap();
+        register_users(
+            &mut context,
+            &mut contract,
+            &[target.clone(), requester.clone()],
+        );
+        contract.send_friend_request(spammer.clone());
+        testing_env!(context.predecessor_account_id(spammer.clone()).build());
+        contract.send_friend_request(target.clone());
+        assert_eq!(contract.get_incoming_request_count(target.clone()), 1);
+        contract.set_friend_labels(user.clone(), vec!["Work".to_string()]);
+        contract.mark_read(user.clone(), 0);
+        contract.save_draft(user.clone(), "Draft".to_string());
+        contract.delete_message(user.clone(), 0, DeleteScope::ForMe);
+        contract.set_relay_key(Some(PublicKey::try_from(vec![0; 33]).unwrap()));
+        contract.relay_nonces.insert(spammer.clone(), 1);
+        testing_env!(context.attached_deposit(1).build());
+        contract.block_user(requester.clone());
+        testing_env!(context.attached_deposit(0).build());
+
+        testing_env!(context.predecessor_account_id(moderator.clone()).build());
+        assert!(!contract.force_remove_user(spammer.clone(), Some(3)));
+        assert!(!contract.users.contains(&spammer));
+        assert!(contract.force_remove_user(spammer.clone(), None));
+
+        assert!(!contract.friends.get(&user).unwrap().contains(&spammer));
+        assert!(!contract.friends.get(&friend).unwrap().contains(&spammer));
+        assert!(!contract.messages.contains_key(&spam_chat_id));
+        assert!(!contract
+            .chat_partners
+            .get(&user)
+            .unwrap()
+            .contains(&spammer));
+        assert_eq!(contract.get_user_stats(user.clone()).friends, 1);
+        assert_eq!(
+            contract.get_messages(user, friend, None, None, None).len(),
+            1
+        );
+        assert!(contract
+            .get_incoming_requests(target.clone(), None, None)
+            .is_empty());
+        assert_eq!(contract.get_incoming_request_count(target), 0);
+        assert!(contract
+            .get_outgoing_requests(requester, None, None)
+            .is_empty());
+        assert!(!contract.incoming_requests.contains_key(&spammer));
+        assert!(!contract.outgoing_requests.contains_key(&spammer));
+        assert!(!contract.blocked_users.contains_key(&spammer));
+        assert!(!contract.friend_labels.contains_key(&spammer));
+        assert!(!contract.read_receipts.contains_key(&spammer));
+        assert!(!contract.drafts.contains_key(&spammer));
+        assert!(!contract.hidden_messages.contains_key(&spammer));
+        assert_eq!(contract.get_relay_key(spammer.clone()), None);
+        assert_eq!(contract.get_relay_nonce(spammer.clone()), 1);
+
+        let logs = get_logs();
+        assert_eq!(
+            logs.last().unwrap(),
+            &format!(
+                r#"EVENT_JSON:{{"standard":"chat","version":"1.0.0","event":"user_force_removed","data":{{"account_id":"{}","moderator_id":"{}"}}}}"#,
+                spammer, moderator
+            )
+        );
+    }
+
+    #[test]
+    fn test_guarded_methods_reject_unauthorized_callers() {
+        fn panic_message(call: impl FnOnce()) -> String {
+            let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(call))
+                .expect_err("the call should have panicked");
+            payload
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| {
+                    payload
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                })
+                .unwrap()
+        }
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.add_moderator(accounts(3));
+        // One yoctoNEAR is attached so the owner check is what fails.
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(1)
+            .build());
+
+        type Call = Box<dyn FnOnce(&mut Contract)>;
+
+        let owner_calls: Vec<Call> = vec![
+            Box::new(|c| {
+                c.admin_register_accounts(vec![]);
+            }),
+            Box::new(|c| {
+                c.recanonicalize_chats(vec![]);
+            }),
+            Box::new(|c| {
+                c.add_verified(accounts(2));
+            }),
+            Box::new(|c| {
+                c.add_relayer(accounts(2));
+            }),
+            Box::new(|c| {
+                c.remove_relayer(accounts(2));
+            }),
+            Box::new(|c| {
+                c.admin_reset("reset contract".to_string(), None);
+            }),
+            Box::new(|c| {
+                c.remove_verified(accounts(2));
+            }),
+            Box::new(|c| c.set_owner(accounts(2))),
+            Box::new(|c| c.set_membership_minter(None, false)),
+            Box::new(|c| {
+                c.prune_empty_chats(vec![]);
+            }),
+            Box::new(|c| c.set_premium_token(None, U128(0))),
+            Box::new(|c| c.set_credit_token(None)),
+            Box::new(|c| c.set_storage_cost_override(None)),
+            Box::new(|c| c.add_banned_substring("spam".to_string())),
+            Box::new(|c| {
+                c.remove_banned_substring("spam".to_string());
+            }),
+            Box::new(|c| {
+                c.add_allowed_reaction("👍".to_string());
+            }),
+            Box::new(|c| {
+                c.remove_allowed_reaction("👍".to_string());
+            }),
+            Box::new(|c| c.set_require_friendship_to_message(false)),
+            Box::new(|c| {
+                c.add_moderator(accounts(2));
+            }),
+            Box::new(|c| {
+                c.remove_moderator(accounts(3));
+            }),
+            Box::new(|c| c.set_max_repeated_messages(1)),
+            Box::new(|c| c.set_max_users(1)),
+            Box::new(|c| c.set_max_group_members(1)),
+            Box::new(|c| c.set_reregistration_cooldown(1)),
+            Box::new(|c| c.propose_owner(accounts(2))),
+            Box::new(|c| c.cancel_ownership_transfer()),
+            Box::new(|c| c.set_default_message_ttl(None)),
+            Box::new(|c| c.set_account_policy(AccountPolicy::default())),
+            Box::new(|c| c.set_reactions_public(false)),
+            Box::new(|c| c.set_welcome_message(String::new())),
+            Box::new(|c| c.set_edit_window(0)),
+        ];
+        for call in owner_calls {
+            assert_eq!(
+                panic_message(|| call(&mut contract)),
+                "Only the owner can call this method."
+            );
+        }
+
+        let moderator_calls: Vec<Call> = vec![
+            Box::new(|c| c.resolve_report(0, ReportStatus::Resolved)),
+            Box::new(|c| {
+                c.force_remove_user(accounts(4), None);
+            }),
+        ];
+        for call in moderator_calls {
+            assert_eq!(
+                panic_message(|| call(&mut contract)),
+                "Only the owner or a moderator can call this method."
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_reports_stops_at_scan_cap() {
+        let owner = accounts(1);
+
+        let mut context = get_context(owner);
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        for id in 0..(MAX_SCAN as u32 + 10) {
+            contract.reports.push(Report {
+                id,
+                reporter_id: accounts(2),
+                reported_id: accounts(3),
+                reason: "Spam".to_string(),
+                created_at_ms: 0,
+                // Only the reports past the cap match the filter.
+                status: if id < MAX_SCAN as u32 {
+                    ReportStatus::Resolved
+                } else {
+                    ReportStatus::Open
+                },
+            });
+        }
+
+        let open = contract.get_reports(Some(ReportStatus::Open), None, None);
+        assert!(open.items.is_empty());
+        assert!(open.truncated);
+
+        let resolved = contract.get_reports(Some(ReportStatus::Resolved), Some(5), None);
+        assert_eq!(resolved.items.len(), 5);
+        assert!(!resolved.truncated);
+    }
+
+    #[test]
+    fn test_reports() {
+        let owner = accounts(1);
+        let user = accounts(2);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        assert_eq!(contract.file_report(accounts(3), "Spam".to_string()), 0);
+        assert_eq!(contract.file_report(accounts(4), "Scam".to_string()), 1);
+        assert_eq!(contract.file_report(accounts(5), "Abuse".to_string()), 2);
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.resolve_report(1, ReportStatus::Resolved);
+        contract.resolve_report(2, ReportStatus::Dismissed);
+
+        let open = contract
+            .get_reports(Some(ReportStatus::Open), None, None)
+            .items;
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].reported_id, accounts(3));
+        let resolved = contract
+            .get_reports(Some(ReportStatus::Resolved), None, None)
+            .items;
+        assert_eq!(resolved[0].id, 1);
+        assert_eq!(contract.get_reports(None, None, Some(1)).items.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a moderator can call this method.")]
+    fn test_resolve_report_not_moderator() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        contract.file_report(accounts(3), "Spam".to_string());
+
+        contract.resolve_report(0, ReportStatus::Resolved);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a moderator can call this method.")]
+    fn test_force_remove_user_not_moderator() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[accounts(2), accounts(3)]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.force_remove_user(accounts(3), None);
+    }
+
+    #[test]
+    fn test_group_chat() {
+        let user = accounts(2);
+        let member = accounts(3);
+        let outsider = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), member.clone(), outsider.clone()],
+        );
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        let group_id = match contract.create_group("Group".to_string(), vec![member.clone()]) {
+            PromiseOrValue::Value(group_id) => group_id,
+            PromiseOrValue::Promise(_) => panic!("premium features are not gated"),
+        };
+
+        testing_env!(context.predecessor_account_id(member.clone()).build());
+        assert_eq!(
+            contract.send_group_message(group_id, "Hello".to_string()),
+            0
+        );
+        assert_eq!(contract.get_user_stats(member.clone()).messages_sent, 1);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        assert!(contract.add_group_member(group_id, outsider.clone()));
+        testing_env!(context.predecessor_account_id(outsider).build());
+        assert_eq!(contract.send_group_message(group_id, "Hi!".to_string()), 1);
+
+        let group = contract.get_group(group_id).unwrap();
+        assert_eq!(group.admin_id, user);
+        assert_eq!(group.members_count, 3);
+        let messages = contract.get_group_messages(group_id, None, None);
+        assert_eq!(messages[0].content, "Hi!");
+        assert_eq!(messages[1].author, member);
+    }
+
+    /// Creates a group of `admin` with `members`, after registering all of them.
+    fn create_test_group(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        admin: &AccountId,
+        members: &[AccountId],
+    ) -> u64 {
+        let mut all = vec![admin.clone()];
+        all.extend(members.iter().cloned());
+        register_users(context, contract, &all);
+
+        testing_env!(context.predecessor_account_id(admin.clone()).build());
+        match contract.create_group("Group".to_string(), members.to_vec()) {
+            PromiseOrValue::Value(group_id) => group_id,
+            PromiseOrValue::Promise(_) => panic!("premium features are not gated"),
+        }
+    }
+
+    #[test]
+    fn test_removed_user_leaves_groups() {
+        let owner = accounts(1);
+        let admin = accounts(2);
+        let member = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        let group_id = create_test_group(
+            &mut context,
+            &mut contract,
+            &admin,
+            std::slice::from_ref(&member),
+        );
+        assert!(contract
+            .group_memberships
+            .get(&member)
+            .unwrap()
+            .contains(&group_id));
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        assert!(contract.force_remove_user(member.clone(), None));
+        assert_eq!(contract.get_group(group_id).unwrap().members_count, 1);
+        assert!(!contract.group_memberships.contains_key(&member));
+    }
+
+    #[test]
+    #[should_panic(expected = "You must be a user to send a message.")]
+    fn test_send_group_message_removed_user() {
+        let owner = accounts(1);
+        let admin = accounts(2);
+        let member = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        let group_id = create_test_group(
+            &mut context,
+            &mut contract,
+            &admin,
+            std::slice::from_ref(&member),
+        );
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.force_remove_user(member.clone(), None);
+
+        testing_env!(context.predecessor_account_id(member).build());
+        contract.send_group_message(group_id, "Still here".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "The message contains banned content.")]
+    fn test_send_group_message_with_banned_substring() {
+        let admin = accounts(2);
+        let member = accounts(3);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.add_banned_substring("spam".to_string());
+        let group_id = create_test_group(
+            &mut context,
+            &mut contract,
+            &admin,
+            std::slice::from_ref(&member),
+        );
+
+        contract.send_group_message(group_id, "Buy SPAM now".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "You have already sent this message too many times in a row.")]
+    fn test_send_group_message_repeated() {
+        let admin = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        let group_id = create_test_group(&mut context, &mut contract, &admin, &[accounts(3)]);
+
+        for _ in 0..=DEFAULT_MAX_REPEATED_MESSAGES {
+            contract.send_group_message(group_id, "Hello".to_string());
+        }
+    }
+
+    #[test]
+    fn test_max_group_members() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let members = [accounts(3), accounts(4), accounts(5)];
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        assert_eq!(contract.get_max_group_members(), DEFAULT_MAX_GROUP_MEMBERS);
+        contract.set_max_group_members(3);
+        assert_eq!(contract.get_max_group_members(), 3);
+
+        let mut all = vec![user.clone()];
+        all.extend(members.iter().cloned());
+        register_users(&mut context, &mut contract, &all);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        let group_id = match contract.create_group(
+            "Group".to_string(),
+            vec![members[0].clone(), members[0].clone(), user.clone()],
+        ) {
+            PromiseOrValue::Value(group_id) => group_id,
+            PromiseOrValue::Promise(_) => panic!("premium features are not gated"),
+        };
+        assert!(contract.add_group_member(group_id, members[1].clone()));
+        assert!(!contract.add_group_member(group_id, members[1].clone()));
+        assert_eq!(contract.get_group(group_id).unwrap().members_count, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "A group can not have more than 3 members.")]
+    fn test_add_group_member_over_max() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let members = [accounts(3), accounts(4), accounts(5)];
+
+        let mut context = get_context(owner);
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_max_group_members(3);
+
+        let mut all = vec![user.clone()];
+        all.extend(members.iter().cloned());
+        register_users(&mut context, &mut contract, &all);
+
+        testing_env!(context.predecessor_account_id(user).build());
+        let group_id = match contract.create_group(
+            "Group".to_string(),
+            vec![members[0].clone(), members[1].clone()],
+        ) {
+            PromiseOrValue::Value(group_id) => group_id,
+            PromiseOrValue::Promise(_) => panic!("premium features are not gated"),
+        };
+        contract.add_group_member(group_id, members[2].clone());
+    }
+
+    #[test]
+    #[should_panic(expected = "A group can not have more than 3 members.")]
+    fn test_create_group_over_max() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let members = vec![accounts(3), accounts(4), accounts(5)];
+
+        let mut context = get_context(owner);
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_max_group_members(3);
+
+        let mut all = vec![user.clone()];
+        all.extend(members.iter().cloned());
+        register_users(&mut context, &mut contract, &all);
+
+        testing_env!(context.predecessor_account_id(user).build());
+        contract.create_group("Group".to_string(), members);
+    }
+
+    #[test]
+    fn test_react_to_group_message() {
+        let user = accounts(2);
+        let member = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), member.clone()]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.create_group("Group".to_string(), vec![member.clone()]);
+        contract.send_group_message(0, "Hello".to_string());
+        assert!(contract.get_group_message_reactions(0, 0).is_empty());
+
+        testing_env!(context.predecessor_account_id(member).build());
+        assert!(contract.react_to_group_message(0, 0, "👍".to_string()));
+        assert!(!contract.react_to_group_message(0, 0, "👍".to_string()));
+        testing_env!(context.predecessor_account_id(user).build());
+        assert!(contract.react_to_group_message(0, 0, "👍".to_string()));
+        assert!(contract.react_to_group_message(0, 0, "🎉".to_string()));
+
+        let mut reactions = contract.get_group_message_reactions(0, 0);
+        reactions.sort();
+        assert_eq!(
+            reactions,
+            vec![(&"🎉".to_string(), 1), (&"👍".to_string(), 2)]
+        );
+        assert!(contract.get_group_message_reactions(0, 1).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "You must be a member of the group to react to its messages.")]
+    fn test_react_to_group_message_not_member() {
+        let user = accounts(2);
+        let outsider = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), outsider.clone()],
+        );
+
+        testing_env!(context.predecessor_account_id(user).build());
+        contract.create_group("Group".to_string(), vec![]);
+        contract.send_group_message(0, "Hello".to_string());
+
+        testing_env!(context.predecessor_account_id(outsider).build());
+        contract.react_to_group_message(0, 0, "👍".to_string());
+    }
+
+    #[test]
+    fn test_mute_group_member() {
+        let user = accounts(2);
+        let member = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), member.clone()]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.create_group("Group".to_string(), vec![member.clone()]);
+        contract.mute_member(0, member.clone(), 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(member)
+            .block_timestamp(1_000_000_000)
+            .build());
+        assert_eq!(contract.send_group_message(0, "Hello".to_string()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "You are muted in this group.")]
+    fn test_muted_member_can_not_send() {
+        let user = accounts(2);
+        let member = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), member.clone()]);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.create_group("Group".to_string(), vec![member.clone()]);
+        contract.mute_member(0, member.clone(), 1_000);
+
+        testing_env!(context.predecessor_account_id(member).build());
+        contract.send_group_message(0, "Hello".to_string());
+    }
+
+    fn unwrap_value(result: PromiseOrValue<U128>) -> u128 {
+        match result {
+            PromiseOrValue::Value(value) => value.0,
+            PromiseOrValue::Promise(_) => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_ft_on_transfer() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let stranger = accounts(3);
+        let token = "token".parse::<AccountId>().unwrap();
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.set_credit_token(Some(token.clone()));
+
+        testing_env!(context.predecessor_account_id(token).build());
+        let result = contract.ft_on_transfer(user.clone(), U128(100), String::new());
+        assert_eq!(unwrap_value(result), 0);
+        let result = contract.ft_on_transfer(user.clone(), U128(50), String::new());
+        assert_eq!(unwrap_value(result), 0);
+        assert_eq!(contract.get_credit_balance(user.clone()), U128(150));
+
+        // Unknown messages and senders are refunded in full.
+        let result = contract.ft_on_transfer(user.clone(), U128(30), "stake".to_string());
+        assert_eq!(unwrap_value(result), 30);
+        let result = contract.ft_on_transfer(stranger.clone(), U128(20), String::new());
+        assert_eq!(unwrap_value(result), 20);
+        assert_eq!(contract.get_credit_balance(user), U128(150));
+        assert_eq!(contract.get_credit_balance(stranger), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the credit token can be deposited.")]
+    fn test_ft_on_transfer_from_other_token() {
+        let owner = accounts(1);
+        let user = accounts(2);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        testing_env!(context.predecessor_account_id(owner).build());
+        contract.set_credit_token(Some("token".parse().unwrap()));
+
+        testing_env!(context
+            .predecessor_account_id("fake-token".parse().unwrap())
+            .build());
+        contract.ft_on_transfer(user, U128(100), String::new());
+    }
+
+    /// Sets up `user` with `amount` credits of the `token` account.
+    fn deposit_credits(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        user: &AccountId,
+        amount: u128,
+    ) -> AccountId {
+        let token = "token".parse::<AccountId>().unwrap();
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_credit_token(Some(token.clone()));
+        testing_env!(context.predecessor_account_id(token.clone()).build());
+        contract.ft_on_transfer(user.clone(), U128(amount), String::new());
+
+        token
+    }
+
+    #[test]
+    fn test_withdraw_credits() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        let token = deposit_credits(&mut context, &mut contract, &user, 100);
+
+        testing_env!(context
+            .predecessor_account_id(user.clone())
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_credits(U128(60));
+        assert_eq!(contract.get_credit_balance(user.clone()), U128(40));
+        assert_eq!(get_created_receipts()[0].receiver_id, token);
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(contract.on_credits_withdrawn(user.clone(), U128(60)));
+        assert_eq!(contract.get_credit_balance(user), U128(40));
+    }
+
+    #[test]
+    fn test_set_credit_token_with_outstanding_credits() {
+        let user = accounts(2);
+        let other_token = "other-token".parse::<AccountId>().unwrap();
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        deposit_credits(&mut context, &mut contract, &user, 100);
+        assert_eq!(contract.total_credits, 100);
+
+        testing_env!(context
+            .predecessor_account_id(user.clone())
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_credits(U128(100));
+        assert_eq!(contract.total_credits, 100);
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.on_credits_withdrawn(user, U128(100));
+        assert_eq!(contract.total_credits, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_credit_token(Some(other_token.clone()));
+        assert_eq!(contract.get_credit_token(), Some(&other_token));
+    }
+
+    #[test]
+    #[should_panic(expected = "The credit token can not change while credits are outstanding.")]
+    fn test_set_credit_token_with_outstanding_credits_fails() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        deposit_credits(&mut context, &mut contract, &user, 100);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_credit_token(Some("other-token".parse().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough credits.")]
+    fn test_withdraw_credits_over_balance() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        deposit_credits(&mut context, &mut contract, &user, 100);
+
+        testing_env!(context
+            .predecessor_account_id(user)
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_credits(U128(101));
+    }
+
+    #[test]
+    fn test_withdraw_credits_transfer_failure() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        deposit_credits(&mut context, &mut contract, &user, 100);
+
+        testing_env!(context
+            .predecessor_account_id(user.clone())
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_credits(U128(100));
+        assert_eq!(contract.get_credit_balance(user.clone()), U128(0));
+
+        testing_env!(
+            context.predecessor_account_id(contract_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.on_credits_withdrawn(user.clone(), U128(100)));
+        assert_eq!(contract.get_credit_balance(user), U128(100));
+    }
+
+    #[test]
+    fn test_create_group_with_premium_balance() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let token = "token".parse::<AccountId>().unwrap();
+
+        let mut context = get_context(owner);
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_premium_token(Some(token.clone()), U128(100));
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        let result = contract.create_group("Group".to_string(), vec![]);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        assert_eq!(get_created_receipts()[0].receiver_id, token);
+        assert!(contract.get_group(0).is_none());
+
+        testing_env!(context.predecessor_account_id(contract_account()).build());
+        let group_id = contract.on_premium_balance_checked(
+            user.clone(),
+            "Group".to_string(),
+            vec![],
+            Ok(U128(100)),
+        );
+        assert_eq!(contract.get_group(group_id).unwrap().admin_id, user);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough premium tokens to create a group.")]
+    fn test_create_group_without_premium_balance() {
+        let owner = accounts(1);
+        let user = accounts(2);
+
+        let mut context = get_context(owner);
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_premium_token(Some("token".parse().unwrap()), U128(100));
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        testing_env!(context.predecessor_account_id(contract_account()).build());
+        contract.on_premium_balance_checked(user, "Group".to_string(), vec![], Ok(U128(99)));
+    }
+
+    #[test]
+    fn test_get_users_with_profiles() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[accounts(2), accounts(3), accounts(4)],
+        );
+        for user in [accounts(2), accounts(3)] {
+            testing_env!(context.predecessor_account_id(user.clone()).build());
+            contract.set_display_name(user.to_string());
+        }
+
+        let users = contract.get_users_with_profiles(None, None);
+        assert_eq!(users.len(), 3);
+        assert_eq!(users[0], (&accounts(4), None));
+        assert_eq!(users[1].1.unwrap().display_name, accounts(3).to_string());
+        assert_eq!(users[2].1.unwrap().display_name, accounts(2).to_string());
+    }
+
+    #[test]
+    fn test_bulk_get_profiles() {
+        let user = accounts(2);
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.set_display_name("Bob".to_string());
+
+        let profiles = contract.bulk_get_profiles(vec![accounts(4), user.clone()]);
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0], (accounts(4), None));
+        assert_eq!(profiles[1].0, user);
+        assert_eq!(profiles[1].1.unwrap().display_name, "Bob");
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot get more than 100 profiles at once.")]
+    fn test_bulk_get_profiles_too_many() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(String::new());
+
+        contract.bulk_get_profiles(vec![accounts(2); MAX_PROFILES_BATCH + 1]);
+    }
+
+    #[test]
+    fn test_storage_cost_override() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        assert_eq!(
+            contract.get_storage_byte_cost(),
+            U128(env::storage_byte_cost())
+        );
+
+        contract.set_storage_cost_override(Some(U128(10)));
+        assert_eq!(contract.get_storage_byte_cost(), U128(10));
+        assert_eq!(
+            contract.estimate_message_deposit(50),
+            U128((MESSAGE_STORAGE_OVERHEAD as u128 + 50) * 10)
+        );
+
+        contract.set_storage_cost_override(None);
+        assert_eq!(
+            contract.get_storage_byte_cost(),
+            U128(env::storage_byte_cost())
+        );
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let user = accounts(2);
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+        assert_eq!(contract.get_last_active(user.clone()), None);
+
+        testing_env!(context
+            .predecessor_account_id(user.clone())
+            .block_timestamp(5_000_000)
+            .build());
+        contract.heartbeat();
+        assert_eq!(contract.get_last_active(user.clone()), Some(5));
+
+        testing_env!(context.block_timestamp(9_000_000).build());
+        contract.heartbeat();
+        assert_eq!(contract.get_last_active(user), Some(9));
+    }
+
+    #[test]
+    #[should_panic(expected = "You must be a user to send a heartbeat.")]
+    fn test_heartbeat_requires_user() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.heartbeat();
+    }
+
+    #[test]
+    fn test_ping_chat() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let chat_id = contract
+            .send_message(friend.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+        assert_eq!(
+            contract.get_chat_presence(user.clone(), friend.clone()),
+            (None, None)
+        );
+
+        testing_env!(context
+            .predecessor_account_id(friend.clone())
+            .block_timestamp(5_000_000)
+            .build());
+        contract.ping_chat(user.clone());
+        assert_eq!(
+            contract.get_chat_presence(user.clone(), friend.clone()),
+            (None, Some(5))
+        );
+        assert_eq!(
+            contract.get_chat_presence(friend.clone(), user.clone()),
+            (Some(5), None)
+        );
+
+        testing_env!(context
+            .predecessor_account_id(user.clone())
+            .block_timestamp(9_000_000)
+            .build());
+        contract.ping_chat(friend.clone());
+        assert_eq!(
+            contract.get_chat_presence(user.clone(), friend.clone()),
+            (Some(9), Some(5))
+        );
+        assert_eq!(
+            contract.get_messages_by_chat_id(chat_id, None, None).len(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no chat with this account.")]
+    fn test_ping_chat_without_chat() {
+        let user = accounts(2);
+        let stranger = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), stranger]);
+        contract.ping_chat(user);
+    }
+
+    #[test]
+    fn test_claim_username() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        contract.claim_username("alice_01".to_string());
+        assert_eq!(
+            contract.resolve_username("alice_01".to_string()),
+            Some(&user)
+        );
+
+        contract.claim_username("alice".to_string());
+        assert_eq!(contract.resolve_username("alice_01".to_string()), None);
+        assert_eq!(
+            contract.get_username(user.clone()),
+            Some(&"alice".to_string())
+        );
+
+        testing_env!(context.attached_deposit(1).build());
+        assert!(contract.delete_account(None));
+        assert_eq!(contract.resolve_username("alice".to_string()), None);
+    }
+
+    #[test]
+    fn test_reregister_after_cooldown() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_reregistration_cooldown(1_000);
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        testing_env!(context.attached_deposit(1).build());
+        assert!(contract.delete_account(None));
+
+        testing_env!(context.block_timestamp(1_000_000_000).build());
+        assert!(matches!(
+            contract.create_account(),
+            PromiseOrValue::Value(true)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "This account was deleted recently and can not register again yet.")]
+    fn test_reregister_within_cooldown() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_reregistration_cooldown(1_000);
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        testing_env!(context.attached_deposit(1).build());
+        contract.delete_account(None);
+        contract.create_account();
+    }
+
+    #[test]
+    #[should_panic(expected = "This account was deleted recently and can not register again yet.")]
+    fn test_reregister_with_max_cooldown() {
+        let user = accounts(2);
+
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.set_reregistration_cooldown(u64::MAX);
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        testing_env!(context
+            .attached_deposit(1)
+            .block_timestamp(1_000_000_000)
+            .build());
+        contract.delete_account(None);
+        contract.create_account();
+    }
+
+    #[test]
+    #[should_panic(expected = "The username is already taken.")]
+    fn test_claim_taken_username() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[accounts(2), accounts(3)]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_username("alice".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.claim_username("alice".to_string());
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Usernames must be 3 to 32 lowercase letters, digits or underscores."
+    )]
+    fn test_claim_invalid_username() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[accounts(2)]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_username("Alice!".to_string());
+    }
+
+    #[test]
+    fn test_two_step_ownership_transfer() {
+        let owner = accounts(1);
+        let new_owner = accounts(2);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = Contract::new(String::new());
+        contract.propose_owner(new_owner.clone());
+        assert_eq!(contract.get_pending_owner(), Some(&new_owner));
+        assert_eq!(contract.get_owner(), &owner);
+
+        testing_env!(context.predecessor_account_id(new_owner.clone()).build());
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner(), &new_owner);
+        assert_eq!(contract.get_pending_owner(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the proposed owner can accept the ownership.")]
+    fn test_accept_ownership_by_wrong_account() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = Contract::new(String::new());
+        contract.propose_owner(accounts(2));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_ownership();
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the proposed owner can accept the ownership.")]
+    fn test_cancel_ownership_transfer() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = Contract::new(String::new());
+        contract.propose_owner(accounts(2));
+        contract.cancel_ownership_transfer();
+        assert_eq!(contract.get_pending_owner(), None);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.accept_ownership();
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_cancel_ownership_transfer_without_deposit() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = Contract::new(String::new());
+        contract.propose_owner(accounts(2));
+
+        testing_env!(context.attached_deposit(0).build());
+        contract.cancel_ownership_transfer();
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_set_owner_without_deposit() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        contract.set_owner(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_delete_account_without_deposit() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        contract.delete_account(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_set_recovery_delegate_without_deposit() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, std::slice::from_ref(&user));
+
+        contract.set_recovery_delegate(accounts(4));
+    }
+
+    #[test]
+    fn test_add_existing_friend_does_not_grow_storage() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        // Persisting the state flushes the collections, as at the end of a call.
+        env::state_write(&contract);
+        drop(contract);
+        let mut contract: Contract = env::state_read().unwrap();
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        let storage_usage = env::storage_usage();
+        assert_eq!(contract.add_friend(user), FriendAddResult::AlreadyFriends);
+        env::state_write(&contract);
+        drop(contract);
+
+        assert_eq!(env::storage_usage(), storage_usage);
+    }
+
+    #[test]
+    fn test_get_friends_with_profiles() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other);
+        let profile = Profile {
+            display_name: "Friend".to_string(),
+            bio: Some("Hi there".to_string()),
+            allow_messages_from: MessagePolicy::default(),
+        };
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.set_profile(profile.clone());
+
+        let mut friends = contract.get_friends_with_profiles(user.clone(), None, None);
+        friends.sort_by_key(|(friend_id, _)| *friend_id);
+        assert_eq!(friends, vec![(&friend, Some(&profile)), (&other, None)]);
+        assert_eq!(
+            contract
+                .get_friends_with_profiles(user.clone(), Some(1), Some(1))
+                .len(),
+            1
+        );
+        assert!(contract
+            .get_friends_with_profiles(accounts(5), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_friendship_storage_usage() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        testing_env!(get_context(user.clone()).build());
+        let prefix = || StorageKey::FriendOfUser {
+            user_id: user.clone(),
+        };
+
+        // The previous layout mapped every friend to an always-true flag.
+        let storage_usage = env::storage_usage();
+        let mut friends_map: UnorderedMap<AccountId, bool> = UnorderedMap::new(prefix());
+        friends_map.insert(friend.clone(), true);
+        friends_map.flush();
+        let map_bytes = env::storage_usage() - storage_usage;
+        friends_map.clear();
+        friends_map.flush();
+        assert_eq!(env::storage_usage(), storage_usage);
+
+        let mut friends_set: UnorderedSet<AccountId> = UnorderedSet::new(prefix());
+        friends_set.insert(friend.clone());
+        friends_set.flush();
+        let set_bytes = env::storage_usage() - storage_usage;
+        // Each side of a friendship no longer stores the borsh-encoded flag.
+        assert_eq!(map_bytes - set_bytes, 1);
+    }
+
+    #[test]
+    fn test_friend_edge_storage_usage() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        testing_env!(get_context(user.clone()).build());
+        let storage_usage = env::storage_usage();
+        for (user_id, friend_id) in [(&user, &friend), (&friend, &user)] {
+            let mut friends: UnorderedSet<AccountId> =
+                UnorderedSet::new(StorageKey::FriendOfUser {
+                    user_id: user_id.clone(),
+                });
+            friends.insert(friend_id.clone());
+            friends.flush();
+        }
+        let index_bytes = env::storage_usage() - storage_usage;
+
+        let storage_usage = env::storage_usage();
+        let mut friend_edges: UnorderedSet<CryptoHash> = UnorderedSet::new(StorageKey::FriendEdges);
+        friend_edges.insert(env::keccak256_array(b"user:friend"));
+        friend_edges.flush();
+        let edge_bytes = env::storage_usage() - storage_usage;
+        // The edge comes on top of both sides of the index, which `friends` keeps so
+        // that each user's friends can be listed.
+        assert!(edge_bytes > 0);
+        assert!(edge_bytes < index_bytes);
+    }
+
+    #[test]
+    fn test_add_and_remove_friend_symmetric() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        assert!(contract.friends.get(&user).unwrap().contains(&friend));
+        assert!(contract.friends.get(&friend).unwrap().contains(&user));
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.remove_friend(user.clone());
+        assert!(contract.friends.get(&user).unwrap().is_empty());
+        assert!(contract.friends.get(&friend).unwrap().is_empty());
+        assert_eq!(contract.get_degree(user), 0);
+        assert_eq!(contract.get_degree(friend), 0);
+    }
+
+    #[test]
+    fn test_get_social_state() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), accounts(3), accounts(4), accounts(5)],
+        );
+        befriend(&mut context, &mut contract, &user, &accounts(3));
+        contract.send_friend_request(accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.send_friend_request(user.clone());
+        testing_env!(context
+            .predecessor_account_id(user.clone())
+            .attached_deposit(1)
+            .build());
+        contract.block_user(accounts(1));
+
+        assert_eq!(
+            contract.get_social_state(user),
+            SocialState {
+                friends: vec![&accounts(3)],
+                incoming: vec![&accounts(5)],
+                outgoing: vec![&accounts(4)],
+                blocked: vec![&accounts(1)],
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You have been blocked by this user.")]
+    fn test_blocked_user_cannot_send_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        testing_env!(context
+            .predecessor_account_id(friend.clone())
+            .attached_deposit(1)
+            .build());
+        assert!(contract.block_user(user.clone()));
+        testing_env!(context.predecessor_account_id(user).build());
+        contract.send_message(friend, "Hello".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_block_user_without_deposit() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+
+        contract.block_user(accounts(3));
+    }
+
+    #[test]
+    fn test_remove_friend_cleans_chat_metadata() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.save_draft(friend.clone(), "See you".to_string());
+        assert_eq!(
+            contract.get_read_receipt(user.clone(), friend.clone()),
+            Some(0)
+        );
+        assert_eq!(
+            contract.get_draft(user.clone(), friend.clone()),
+            Some(&"See you".to_string())
+        );
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.remove_friend(user.clone());
+
+        assert_eq!(
+            contract.get_read_receipt(user.clone(), friend.clone()),
+            None
+        );
+        assert_eq!(contract.get_draft(user, friend), None);
+    }
+
+    #[test]
+    fn test_get_contract_stats() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &friend, &user);
+        befriend(&mut context, &mut contract, &user, &other_friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(other_friend.clone(), "Hello".to_string(), None, None);
+        contract.remove_friend(other_friend);
+        contract.create_group("Group".to_string(), vec![friend]);
+        contract.send_group_message(0, "Hello".to_string());
+
+        let stats = contract.get_contract_stats();
+        assert_eq!(stats.users, 3);
+        assert_eq!(stats.total_messages, 3);
+        assert_eq!(stats.total_friendships, 1);
+        assert_eq!(stats.storage_usage, env::storage_usage());
+    }
+
+    #[test]
+    #[should_panic(expected = "You cannot add the contract as friend.")]
+    fn test_add_contract_as_friend() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user, contract_account()]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.add_friend(contract_account());
+    }
+
+    #[test]
+    fn test_friendship_is_a_single_edge() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+
+        befriend(&mut context, &mut contract, &user, &friend);
+        assert!(contract.are_friends(user.clone(), friend.clone()));
+        assert!(contract.are_friends(friend.clone(), user.clone()));
+        assert_eq!(contract.friend_edges.len(), 1);
+
+        assert_eq!(
+            contract.add_friend(friend.clone()),
+            FriendAddResult::AlreadyFriends
+        );
+        befriend(&mut context, &mut contract, &other_friend, &user);
+        assert_eq!(contract.friend_edges.len(), 2);
+
+        contract.remove_friend(user.clone());
+        assert!(!contract.are_friends(user.clone(), other_friend));
+        assert!(contract.are_friends(friend, user));
+        assert_eq!(contract.friend_edges.len(), 1);
+    }
+
+    #[test]
+    fn test_friend_labels() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other_friend);
+
+        contract.set_friend_labels(
+            friend.clone(),
+            vec!["close".to_string(), "work".to_string()],
+        );
+        contract.set_friend_labels(other_friend.clone(), vec!["work".to_string()]);
+
+        assert_eq!(
+            contract
+                .get_friends_by_label(user.clone(), "close".to_string())
+                .items,
+            vec![&friend]
+        );
+        assert_eq!(
+            contract
+                .get_friends_by_label(user.clone(), "work".to_string())
+                .items
+                .len(),
+            2
+        );
+        assert!(contract
+            .get_friends_by_label(friend.clone(), "close".to_string())
+            .items
+            .is_empty());
+
+        contract.remove_friend(friend.clone());
+        assert!(contract.get_friend_labels(user.clone(), friend).is_empty());
+        assert_eq!(
+            contract
+                .get_friends_by_label(user, "work".to_string())
+                .items,
+            vec![&other_friend]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Labels must be between 1 and 32 bytes long.")]
+    fn test_friend_labels_too_long() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        contract.set_friend_labels(friend, vec!["a".repeat(33)]);
+    }
+
+    #[test]
+    fn test_get_degree() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &other_friend, &user);
+        assert_eq!(contract.get_degree(user.clone()), 2);
+        assert_eq!(contract.get_degree(friend.clone()), 1);
+
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.remove_friend(friend.clone());
+        assert_eq!(contract.get_degree(user), 1);
+        assert_eq!(contract.get_degree(friend), 0);
+    }
+
+    #[test]
+    fn test_recovery() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let delegate = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        testing_env!(context.attached_deposit(1).build());
+        contract.set_recovery_delegate(delegate.clone());
+        assert_eq!(
+            contract.get_recovery_delegate(user.clone()),
+            Some(&delegate)
+        );
+
+        testing_env!(context
+            .predecessor_account_id(delegate.clone())
+            .block_timestamp(1_000_000)
+            .build());
+        contract.initiate_recovery(user.clone());
+        assert_eq!(contract.get_pending_recovery(user.clone()), Some(1));
+
+        testing_env!(context
+            .block_timestamp((1 + RECOVERY_DELAY_MS) * 1_000_000)
+            .build());
+        contract.complete_recovery(user.clone());
+        assert!(contract.is_frozen(user.clone()));
+        assert_eq!(contract.get_pending_recovery(user.clone()), None);
+
+        contract.unfreeze_account(user.clone());
+        assert!(!contract.is_frozen(user));
+    }
+
+    #[test]
+    #[should_panic(expected = "The recovery delay has not elapsed yet.")]
+    fn test_complete_recovery_before_delay() {
+        let user = accounts(2);
+        let delegate = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+        testing_env!(context.attached_deposit(1).build());
+        contract.set_recovery_delegate(delegate.clone());
+
+        testing_env!(context.predecessor_account_id(delegate).build());
+        contract.initiate_recovery(user.clone());
+
+        testing_env!(context
+            .block_timestamp((RECOVERY_DELAY_MS - 1) * 1_000_000)
+            .build());
+        contract.complete_recovery(user);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the recovery delegate can recover this account.")]
+    fn test_initiate_recovery_unauthorized_delegate() {
+        let user = accounts(2);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.create_account();
+        testing_env!(context.attached_deposit(1).build());
+        contract.set_recovery_delegate(accounts(4));
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.initiate_recovery(user);
+    }
+
+    #[test]
+    #[should_panic(expected = "This account is frozen.")]
+    fn test_frozen_account_cannot_send_message() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let delegate = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        testing_env!(context.attached_deposit(1).build());
+        contract.set_recovery_delegate(delegate.clone());
+
+        testing_env!(context.predecessor_account_id(delegate).build());
+        contract.initiate_recovery(user.clone());
+        testing_env!(context
+            .block_timestamp(RECOVERY_DELAY_MS * 1_000_000)
+            .build());
+        contract.complete_recovery(user.clone());
+
+        testing_env!(context.predecessor_account_id(user).build());
+        contract.send_message(friend, "Hello World!".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_get_messages_after_id() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other_friend);
+        for i in 0..5 {
+            contract.send_message(friend.clone(), format!("Message {}", i), None, None);
+            // Interleave another chat so ids are not contiguous.
+            contract.send_message(other_friend.clone(), format!("Hi {}!", i), None, None);
+        }
+
+        let mut contents = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page =
+                contract.get_messages_after_id(user.clone(), friend.clone(), cursor, Some(2));
+            contents.extend(page.messages.iter().map(|message| message.content.clone()));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        assert_eq!(
+            contents,
+            (0..5)
+                .map(|i| format!("Message {}", i))
+                .collect::<Vec<String>>()
+        );
+
+        let page = contract.get_messages_after_id(user, friend, Some(8), None);
+        assert_eq!(page.messages.len(), 0);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn test_chat_id_string_round_trip() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        let chat_id = contract
+            .send_message(friend.clone(), "Hello World!".to_string(), None, None)
+            .chat_id;
+        assert_eq!(chat_id, contract.get_chat_id(friend, user));
+
+        let json = near_sdk::serde_json::to_string(&chat_id).unwrap();
+        assert_eq!(json, format!("\"{}\"", String::from(&chat_id)));
+
+        let parsed: Base58CryptoHash = near_sdk::serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, chat_id);
+
+        let messages = contract.get_messages_by_chat_id(parsed, None, None);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Hello World!");
+    }
+
+    #[test]
+    fn test_chat_id_is_symmetric() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(String::new());
+
+        assert_eq!(
+            contract.get_chat_id(accounts(2), accounts(3)),
+            contract.get_chat_id(accounts(3), accounts(2))
+        );
+    }
+
+    #[test]
+    fn test_chat_id_depends_on_salt() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new("first".to_string());
+        let other_contract = Contract::new("second".to_string());
+
+        assert_ne!(
+            contract.get_chat_id(accounts(2), accounts(3)),
+            other_contract.get_chat_id(accounts(2), accounts(3))
+        );
+    }
+
     #[test]
-    fn test_new() {
-        let mut context = get_context(accounts(1));
+    fn test_chat_id_pairs_do_not_collide() {
+        let context = get_context(accounts(1));
         testing_env!(context.build());
-        let contract = Contract::new();
-        testing_env!(context.is_view(true).build());
-        assert_eq!(contract.get_users_length(), 0);
+        let contract = Contract::new(String::new());
+
+        // Without a delimiter both pairs hash "aabbcc".
+        assert_ne!(
+            contract.get_chat_id("aa".parse().unwrap(), "bbcc".parse().unwrap()),
+            contract.get_chat_id("aabb".parse().unwrap(), "cc".parse().unwrap())
+        );
     }
 
     #[test]
-    #[should_panic(expected = "The contract is not initialized")]
-    fn test_default() {
+    fn test_get_chat_participants() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &friend, &user);
+        let chat_id = contract
+            .send_message(user.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+
+        assert_eq!(
+            contract.get_chat_participants(chat_id),
+            Some(&(user, friend))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The chat id is already used by another pair of accounts.")]
+    fn test_chat_id_collision_is_detected() {
+        let user: AccountId = "aa".parse().unwrap();
+        let friend: AccountId = "bbcc".parse().unwrap();
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        // Simulates a chat created for "aabb" and "cc" when both pairs hashed "aabbcc".
+        let chat_id = contract.internal_chat_id(&user, &friend);
+        contract
+            .chat_participants
+            .insert(chat_id, ("aabb".parse().unwrap(), "cc".parse().unwrap()));
+        assert_ne!(
+            contract.get_chat_participants(chat_id.into()),
+            Some(&(user, friend.clone()))
+        );
+
+        contract.send_message(friend, "Hello".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_chat_id_string_is_symmetric() {
         let context = get_context(accounts(1));
         testing_env!(context.build());
-        let _contract = Contract::default();
+        let contract = Contract::new(String::new());
+
+        let chat_id = contract.get_chat_id_string(accounts(2), accounts(3));
+        assert_eq!(
+            chat_id,
+            contract.get_chat_id_string(accounts(3), accounts(2))
+        );
+        assert_eq!(
+            chat_id,
+            String::from(&contract.get_chat_id(accounts(2), accounts(3)))
+        );
     }
 
     #[test]
-    fn test_create_account() {
+    fn test_batch_mark_read() {
         let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
+
         let mut context = get_context(user.clone());
         testing_env!(context.build());
-        let mut contract = Contract::new();
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other_friend);
 
-        let is_valid_user = contract.create_account();
-        assert!(is_valid_user);
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.send_message(user.clone(), "Hello".to_string(), None, None);
+        contract.send_message(user.clone(), "World".to_string(), None, None);
+        testing_env!(context.predecessor_account_id(other_friend.clone()).build());
+        contract.send_message(user.clone(), "Hi!".to_string(), None, None);
 
-        let is_valid_user = contract.users.contains(&user);
-        assert!(is_valid_user);
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        assert_eq!(
+            contract.get_read_receipt(user.clone(), friend.clone()),
+            None
+        );
+        contract.batch_mark_read(vec![(friend.clone(), 1), (other_friend.clone(), 0)]);
+        assert_eq!(
+            contract.get_read_receipt(user.clone(), friend.clone()),
+            Some(1)
+        );
+        assert_eq!(
+            contract.get_read_receipt(user.clone(), other_friend),
+            Some(0)
+        );
 
-        let users = contract.get_users(None, None);
-        let is_valid_user = users.contains(&&user);
-        assert!(is_valid_user);
+        // Read receipts never move backwards.
+        contract.mark_read(friend.clone(), 0);
+        assert_eq!(
+            contract.get_read_receipt(user.clone(), friend.clone()),
+            Some(1)
+        );
+        // Senders have read the chat up to their own messages.
+        assert_eq!(contract.get_read_receipt(friend, user), Some(1));
     }
 
     #[test]
-    fn test_add_friend() {
+    #[should_panic(expected = "The message to mark as read does not exist.")]
+    fn test_batch_mark_read_invalid_entry() {
         let user = accounts(2);
         let friend = accounts(3);
 
         let mut context = get_context(user.clone());
         testing_env!(context.build());
-        let mut contract = Contract::new();
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
 
-        let is_valid_user = contract.create_account();
-        assert!(is_valid_user);
+        contract.batch_mark_read(vec![(friend.clone(), 0), (friend, 1)]);
+    }
 
-        testing_env!(context.predecessor_account_id(friend.clone()).build());
-        let is_valid_user = contract.create_account();
-        assert!(is_valid_user);
+    #[test]
+    fn test_get_chats_with_unread() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other_friend = accounts(4);
 
-        assert_eq!(contract.get_users_length(), 2);
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other_friend);
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.send_message(user.clone(), "Hello".to_string(), None, None);
+        contract.send_message(user.clone(), "World".to_string(), None, None);
+        testing_env!(context.predecessor_account_id(other_friend.clone()).build());
+        contract.send_message(user.clone(), "Hi!".to_string(), None, None);
 
         testing_env!(context.predecessor_account_id(user.clone()).build());
-        contract.add_friend(friend.clone());
-        let is_friend_added = contract.friends.get(&user).unwrap().get(&friend).unwrap();
-        assert!(*is_friend_added);
+        contract.mark_read(other_friend.clone(), 0);
+
+        assert_eq!(
+            contract.get_chats_with_unread(user.clone()),
+            vec![(friend.clone(), 2)]
+        );
+        // The sender has read their own messages.
+        assert!(contract.get_chats_with_unread(friend).is_empty());
+        assert_eq!(contract.get_unread_count(user, other_friend), 0);
     }
 
     #[test]
-    fn test_send_message() {
+    fn test_get_total_unread() {
         let user = accounts(2);
         let friend = accounts(3);
+        let other_friend = accounts(4);
 
         let mut context = get_context(user.clone());
         testing_env!(context.build());
-        let mut contract = Contract::new();
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other_friend.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other_friend);
+
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        for i in 0..2 {
+            contract.send_message(user.clone(), format!("Message {}", i), None, None);
+        }
+        testing_env!(context.predecessor_account_id(other_friend).build());
+        for i in 0..3 {
+            contract.send_message(user.clone(), format!("Message {}", i), None, None);
+        }
+
+        assert_eq!(contract.get_total_unread(user), 5);
+        assert_eq!(contract.get_total_unread(friend), 0);
+    }
+
+    #[test]
+    fn test_prune_empty_chats() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.create_account();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
         contract.create_account();
         testing_env!(context.predecessor_account_id(friend.clone()).build());
         contract.create_account();
+        contract.add_friend(user.clone());
+        contract.add_friend(accounts(4));
+        let other_chat_id = contract
+            .send_message(accounts(4), "Hi!".to_string(), None, None)
+            .chat_id;
+        let other_chat_hash: CryptoHash = other_chat_id.into();
+
         testing_env!(context.predecessor_account_id(user.clone()).build());
-        contract.add_friend(friend.clone());
+        let chat_id = contract
+            .send_message(friend.clone(), "Hello World!".to_string(), None, None)
+            .chat_id;
+        let chat_hash: CryptoHash = chat_id.into();
+        assert_eq!(
+            contract.chat_participants.get(&chat_hash),
+            Some(&(user.clone(), friend.clone()))
+        );
+        contract.mark_read(friend.clone(), 0);
+        contract.save_draft(friend.clone(), "Draft".to_string());
+        contract.send_poll(
+            friend.clone(),
+            "Lunch?".to_string(),
+            vec!["Yes".to_string(), "No".to_string()],
+        );
+        contract.send_message(friend.clone(), "Oops".to_string(), None, None);
+        contract.delete_message(friend.clone(), 2, DeleteScope::ForEveryone);
+        contract.delete_message(friend.clone(), 1, DeleteScope::ForMe);
+
+        testing_env!(context
+            .predecessor_account_id(friend.clone())
+            .attached_deposit(ONE_NEAR)
+            .build());
+        contract.react_with_tip(user.clone(), 0, "🎉".to_string(), U128(ONE_NEAR));
+        contract.vote_poll(user.clone(), 1, 0);
+        testing_env!(
+            context
+                .predecessor_account_id(contract_account())
+                .attached_deposit(0)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let message_ids: Vec<u64> = contract
+            .messages
+            .get(&chat_hash)
+            .unwrap()
+            .iter()
+            .map(|message| message.id)
+            .collect();
+        contract.on_tip_transferred(message_ids[0], friend.clone(), U128(ONE_NEAR));
+        assert!(contract.reactions.contains_key(&message_ids[0]));
+        assert!(contract.message_tips.contains_key(&message_ids[0]));
+        assert!(contract.poll_tallies.contains_key(&message_ids[1]));
+        assert!(contract.deleted_messages.contains_key(&message_ids[2]));
+        assert!(contract
+            .hidden_messages
+            .get(&user)
+            .unwrap()
+            .contains(&message_ids[1]));
 
         testing_env!(context.predecessor_account_id(user.clone()).build());
+        contract.enable_e2e(friend.clone());
+        contract.clear_chat(friend.clone());
+        assert!(contract.messages.get(&chat_hash).unwrap().is_empty());
+        for message_id in &message_ids {
+            assert!(!contract.reactions.contains_key(message_id));
+            assert!(!contract.message_tips.contains_key(message_id));
+            assert!(!contract.poll_votes.contains_key(message_id));
+            assert!(!contract.poll_tallies.contains_key(message_id));
+            assert!(!contract.deleted_messages.contains_key(message_id));
+        }
+        assert!(contract.hidden_messages.get(&user).unwrap().is_empty());
 
-        let chat_id = contract.send_message(friend, "Hello World!".to_string());
-        println!("{:?}", contract.messages.get(&chat_id).unwrap().get(0));
-        let is_message_added = contract.messages.contains_key(&chat_id);
-        assert!(is_message_added);
+        testing_env!(context.predecessor_account_id(owner).build());
+        let pruned = contract.prune_empty_chats(vec![chat_id, other_chat_id, [0; 32].into()]);
+        assert_eq!(pruned, 1);
+        assert!(!contract.messages.contains_key(&chat_hash));
+        assert!(!contract.chat_participants.contains_key(&chat_hash));
+        assert!(!contract
+            .get_all_chat_ids_for_user(user.clone(), None)
+            .contains(&chat_id));
+        assert!(!contract
+            .get_all_chat_ids_for_user(friend.clone(), None)
+            .contains(&chat_id));
+        assert!(contract
+            .get_all_chat_ids_for_user(friend.clone(), None)
+            .contains(&other_chat_id));
+        assert_eq!(
+            contract.get_read_receipt(user.clone(), friend.clone()),
+            None
+        );
+        assert_eq!(contract.get_draft(user.clone(), friend.clone()), None);
+        // Enabling end-to-end encryption can not be undone, not even by pruning.
+        assert!(contract.is_e2e_enabled(user.clone(), friend.clone()));
+        assert!(!contract.message_repeats.contains_key(&chat_hash));
+        assert!(contract.messages.contains_key(&other_chat_hash));
+        assert_eq!(contract.messages.get(&other_chat_hash).unwrap().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_prune_empty_chats_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.prune_empty_chats(vec![]);
+    }
+
+    #[test]
+    fn test_admin_reset() {
+        let owner = accounts(1);
+        let users = [accounts(2), accounts(3), accounts(4)];
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &users);
+        befriend(&mut context, &mut contract, &users[0], &users[1]);
+        befriend(&mut context, &mut contract, &users[0], &users[2]);
+        let chat_id: CryptoHash = contract
+            .send_message(users[1].clone(), "Hello".to_string(), None, None)
+            .chat_id
+            .into();
+
+        testing_env!(context
+            .predecessor_account_id(owner)
+            .attached_deposit(1)
+            .build());
+        let confirm = format!("reset {}", contract_account());
+        assert_eq!(contract.admin_reset(confirm.clone(), Some(1)), 2);
+        assert_eq!(contract.admin_reset(confirm.clone(), Some(5)), 0);
+        assert_eq!(contract.admin_reset(confirm, None), 0);
+
+        assert_eq!(contract.users.len(), 0);
+        assert!(contract.get_users_from(None, None).is_empty());
+        assert!(!contract.messages.contains_key(&chat_id));
+        for user in users {
+            assert_eq!(contract.get_degree(user.clone()), 0);
+            assert!(contract.friends.get(&user).is_none());
+        }
+        assert_eq!(contract.total_friendships, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        assert!(matches!(
+            contract.create_account(),
+            PromiseOrValue::Value(true)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "The confirmation does not match.")]
+    fn test_admin_reset_without_confirmation() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = Contract::new(String::new());
+        contract.admin_reset("reset".to_string(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_admin_reset_without_deposit() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.admin_reset(format!("reset {}", contract_account()), None);
+    }
+
+    /// The owned counterpart of `MessageView`, which only borrows from the contract.
+    #[derive(BorshDeserialize)]
+    struct OwnedMessageView {
+        chat_id: Base58CryptoHash,
+        index: u32,
+        participants: (AccountId, AccountId),
+        message: Message,
+    }
+
+    #[test]
+    fn test_deleted_messages_are_left_out_of_every_view() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.send_message(friend.clone(), "Oops".to_string(), None, None);
+        contract.send_message(friend.clone(), "Only for me".to_string(), None, None);
+        contract.delete_message(friend.clone(), 1, DeleteScope::ForEveryone);
+        contract.delete_message(friend.clone(), 2, DeleteScope::ForMe);
+
+        let contents = |messages: Vec<&Message>| {
+            messages
+                .iter()
+                .map(|message| message.content.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            contents(contract.get_oldest_messages(user.clone(), friend.clone(), None, None)),
+            vec!["Hello"]
+        );
+        assert_eq!(
+            contents(
+                contract
+                    .get_messages_after_id(user.clone(), friend.clone(), None, None)
+                    .messages
+            ),
+            vec!["Hello"]
+        );
+        let context_views = contract.get_message_context(user.clone(), friend.clone(), 1, 1);
+        assert_eq!(
+            context_views
+                .iter()
+                .map(|view| view.index)
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+        let export = contract.export_chat(user.clone(), friend.clone());
+        assert!(export.contains("Hello"));
+        assert!(!export.contains("Only for me"));
+
+        // Only the author deleted the last message, so the friend still sees it.
+        assert_eq!(
+            contents(contract.get_oldest_messages(friend, user, None, None)),
+            vec!["Hello", "Only for me"]
+        );
+    }
+
+    #[test]
+    fn test_get_messages_borsh() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let chat_id = contract
+            .send_message(friend.clone(), "Hello".to_string(), None, None)
+            .chat_id;
+        contract.send_message(friend.clone(), "How are you?".to_string(), None, None);
+        contract.send_message(friend.clone(), "Oops".to_string(), None, None);
+        contract.delete_message(friend.clone(), 2, DeleteScope::ForEveryone);
+
+        let bytes = contract
+            .get_messages_borsh(user.clone(), friend.clone(), None, None)
+            .try_to_vec()
+            .unwrap();
+        let views: Vec<OwnedMessageView> = BorshDeserialize::try_from_slice(&bytes).unwrap();
+
+        let json = contract.get_messages(user.clone(), friend.clone(), None, None, None);
+        assert_eq!(views.len(), 2);
+        for (view, message) in views.iter().zip(json) {
+            assert_eq!(view.chat_id, chat_id);
+            assert_eq!(view.participants, (user.clone(), friend.clone()));
+            assert_eq!(view.message.id, message.id);
+            assert_eq!(view.message.content, message.content);
+        }
+        assert_eq!(views[0].index, 1);
+        assert_eq!(views[1].index, 0);
+
+        let bytes = contract
+            .get_message_context_borsh(user.clone(), friend.clone(), 1, 1)
+            .try_to_vec()
+            .unwrap();
+        let views: Vec<OwnedMessageView> = BorshDeserialize::try_from_slice(&bytes).unwrap();
+        assert_eq!(
+            views.iter().map(|view| view.index).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert!(contract
+            .get_messages_borsh(user, accounts(4), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_messages_page() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        let page = contract.get_messages_page(user.clone(), friend.clone(), None, Some(5), None);
+        assert!(page.items.is_empty());
+        assert_eq!(page.total, 0);
+
+        for content in ["one", "two", "three"] {
+            contract.send_message(friend.clone(), content.to_string(), None, None);
+        }
+
+        let page = contract.get_messages_page(user.clone(), friend.clone(), None, Some(2), None);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].content, "one");
+
+        for offset in [3, 4, u32::MAX] {
+            let page =
+                contract.get_messages_page(user.clone(), friend.clone(), None, Some(offset), None);
+            assert!(page.items.is_empty());
+            assert_eq!(page.total, 3);
+        }
+    }
+
+    #[test]
+    fn test_search_all_messages() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other);
+        contract.send_message(friend.clone(), "Lunch tomorrow?".to_string(), None, None);
+        contract.send_message(friend.clone(), "Anything else".to_string(), None, None);
+        contract.send_message(
+            other.clone(),
+            "Where do we have LUNCH".to_string(),
+            None,
+            None,
+        );
+        testing_env!(context.predecessor_account_id(friend.clone()).build());
+        contract.send_message(user.clone(), "lunch at noon".to_string(), None, None);
+
+        let result = contract.search_all_messages(user.clone(), "lunch".to_string(), None);
+        assert!(!result.truncated);
+        let mut found: Vec<(&AccountId, u32, &str)> = result
+            .items
+            .iter()
+            .map(|found| {
+                (
+                    found.partner_id,
+                    found.index,
+                    found.message.content.as_str(),
+                )
+            })
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (&friend, 0, "Lunch tomorrow?"),
+                (&friend, 2, "lunch at noon"),
+                (&other, 0, "Where do we have LUNCH"),
+            ]
+        );
+
+        let result = contract.search_all_messages(user.clone(), "lunch".to_string(), Some(1));
+        assert_eq!(result.items.len(), 1);
+        assert!(contract
+            .search_all_messages(user, "dinner".to_string(), None)
+            .items
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_chat_last_activity() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+        assert_eq!(
+            contract.get_chat_last_activity(user.clone(), friend.clone()),
+            None
+        );
+
+        testing_env!(context.block_timestamp(5_000_000).build());
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        testing_env!(context.block_timestamp(9_000_000).build());
+        contract.send_message(friend.clone(), "Are you there?".to_string(), None, None);
+
+        assert_eq!(
+            contract.get_chat_last_activity(friend.clone(), user.clone()),
+            Some(9)
+        );
+        contract.clear_chat(friend.clone());
+        assert_eq!(contract.get_chat_last_activity(user, friend), None);
+    }
+
+    #[test]
+    fn test_get_chat_last_activity_skips_scheduled_messages() {
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(&mut context, &mut contract, &[user.clone(), friend.clone()]);
+        befriend(&mut context, &mut contract, &user, &friend);
+
+        testing_env!(context.block_timestamp(5_000_000).build());
+        contract.schedule_message(friend.clone(), "Later".to_string(), 20);
+        assert_eq!(
+            contract.get_chat_last_activity(user.clone(), friend.clone()),
+            None
+        );
+        testing_env!(context.block_timestamp(9_000_000).build());
+        contract.send_message(friend.clone(), "Hello".to_string(), None, None);
+        contract.schedule_message(friend.clone(), "Even later".to_string(), 30);
+        assert_eq!(
+            contract.get_chat_last_activity(user.clone(), friend.clone()),
+            Some(9)
+        );
+
+        testing_env!(context.block_timestamp(30_000_000).build());
+        assert_eq!(contract.get_chat_last_activity(user, friend), Some(9));
+    }
+
+    #[test]
+    fn test_estimate_chat_size() {
+        let user = accounts(2);
+        let friend = accounts(3);
+        let other = accounts(4);
+
+        let mut context = get_context(user.clone());
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        register_users(
+            &mut context,
+            &mut contract,
+            &[user.clone(), friend.clone(), other.clone()],
+        );
+        befriend(&mut context, &mut contract, &user, &friend);
+        befriend(&mut context, &mut contract, &user, &other);
+        assert_eq!(contract.estimate_chat_size(user.clone(), friend.clone()), 0);
+
+        contract.send_message(friend.clone(), "Hi".to_string(), None, None);
+        let small = contract.estimate_chat_size(user.clone(), friend.clone());
+        assert_eq!(small, MESSAGE_STORAGE_OVERHEAD + 2);
+        assert_eq!(contract.estimate_chat_size(friend, user.clone()), small);
+
+        for i in 0..5 {
+            contract.send_message(other.clone(), format!("A longer message {}", i), None, None);
+        }
+        let large = contract.estimate_chat_size(user, other);
+        assert_eq!(large, 5 * (MESSAGE_STORAGE_OVERHEAD + 18));
+        assert!(large > small);
+    }
+
+    /// The storage keys of the baseline contract. `StorageKey` kept their order, but
+    /// gave `Message` a prefix per chat.
+    #[derive(BorshSerialize, BorshStorageKey)]
+    enum BaselineKey {
+        Users,
+        Messages,
+        Message,
+        Friends,
+        FriendOfUser { user_id: AccountId },
+    }
+
+    /// Writes the state of a baseline contract with `users`, where the accounts of each
+    /// pair in `friendships` are friends.
+    fn write_baseline_state(users: &[AccountId], friendships: &[(AccountId, AccountId)]) {
+        let mut state = LegacyContract {
+            users: UnorderedSet::new(BaselineKey::Users),
+            messages: LookupMap::new(BaselineKey::Messages),
+            friends: LookupMap::new(BaselineKey::Friends),
+        };
+        state.users.extend(users.iter().cloned());
+        for (user_id, friend_id) in friendships {
+            for (user_id, friend_id) in [(user_id, friend_id), (friend_id, user_id)] {
+                state
+                    .friends
+                    .entry(user_id.clone())
+                    .or_insert_with(|| {
+                        LookupMap::new(BaselineKey::FriendOfUser {
+                            user_id: user_id.clone(),
+                        })
+                    })
+                    .insert(friend_id.clone(), true);
+            }
+        }
+        env::state_write(&state);
+    }
+
+    /// Sends a message the way the baseline contract did, in a call of its own.
+    fn baseline_send(
+        user_id: &AccountId,
+        receiver_id: &AccountId,
+        content: &str,
+        created_at_ms: u64,
+    ) {
+        let mut state: LegacyContract = env::state_read().unwrap();
+        let mut chat_id = CryptoHash::default();
+        chat_id.copy_from_slice(&env::keccak256(
+            format!("{}{}", user_id, receiver_id).as_bytes(),
+        ));
+
+        state
+            .messages
+            .entry(chat_id)
+            .or_insert_with(|| Vector::new(BaselineKey::Message))
+            .push(LegacyMessage {
+                author: user_id.clone(),
+                content: content.to_string(),
+                created_at_ms,
+            });
+        env::state_write(&state);
+    }
+
+    #[test]
+    fn test_recanonicalize_chats() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        write_baseline_state(
+            &[user.clone(), friend.clone()],
+            &[(user.clone(), friend.clone())],
+        );
+        baseline_send(&user, &friend, "first", 1);
+        baseline_send(&user, &friend, "second", 2);
+        // Stored at index 0 as well, over "first".
+        baseline_send(&friend, &user, "third", 3);
+
+        let mut contract = Contract::migrate(String::new(), owner.clone());
+        assert_eq!(contract.owner_id, owner);
+        assert_eq!(contract.users.len(), 2);
+        testing_env!(context.block_timestamp(10_000_000).build());
+        befriend(&mut context, &mut contract, &user, &friend);
+        contract.send_message(friend.clone(), "fourth".to_string(), None, None);
+        contract.send_message(friend.clone(), "fifth".to_string(), None, None);
+        let chat_id = contract.internal_chat_id(&user, &friend);
+        contract.messages.get_mut(&chat_id).unwrap()[1].reply_to = Some(0);
+
+        testing_env!(context.predecessor_account_id(owner).build());
+        assert_eq!(
+            contract.recanonicalize_chats(vec![(friend.clone(), user.clone())]),
+            1
+        );
+        let messages = contract.messages.get(&chat_id).unwrap();
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["second", "third", "fourth", "fifth"]);
+        assert_eq!(messages[3].reply_to, Some(2));
+        let ids: Vec<u64> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, [0, 1, LEGACY_MESSAGE_IDS, LEGACY_MESSAGE_IDS + 1]);
+        assert_eq!(contract.total_messages, 4);
+
+        assert_eq!(
+            contract.recanonicalize_chats(vec![(user.clone(), friend.clone())]),
+            0
+        );
+        assert_eq!(contract.messages.get(&chat_id).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_recanonicalize_chats_without_canonical_chat() {
+        let owner = accounts(1);
+        let user = accounts(2);
+        let friend = accounts(3);
+
+        let mut context = get_context(owner.clone());
+        testing_env!(context.build());
+        write_baseline_state(
+            &[user.clone(), friend.clone()],
+            &[(user.clone(), friend.clone())],
+        );
+        baseline_send(&user, &friend, "Hello", 1);
+        let mut contract = Contract::migrate(String::new(), owner);
+        assert!(!contract.are_friends(user.clone(), friend.clone()));
+
+        assert_eq!(
+            contract.recanonicalize_chats(vec![(user.clone(), friend.clone())]),
+            1
+        );
+        let chat_id = contract.internal_chat_id(&user, &friend);
+        assert_eq!(contract.messages.get(&chat_id).unwrap()[0].content, "Hello");
+        assert_eq!(
+            contract.chat_participants.get(&chat_id),
+            Some(&(user.clone(), friend.clone()))
+        );
+        assert!(contract.chat_ids.contains(&chat_id));
+        assert!(contract.are_friends(user.clone(), friend.clone()));
+
+        testing_env!(context.predecessor_account_id(user).build());
+        contract.send_message(friend, "Hi".to_string(), None, None);
+        assert_eq!(contract.messages.get(&chat_id).unwrap().len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot recanonicalize more than 20 chats at once.")]
+    fn test_recanonicalize_chats_too_many() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(String::new());
+        contract.recanonicalize_chats(vec![(accounts(2), accounts(3)); 21]);
     }
 }